@@ -1,10 +1,84 @@
-#[derive(PartialEq, Eq, Debug)]
+use crate::job::{Job, JobId, JobOptions};
+use crate::Url;
+use tokio::sync::oneshot;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub enum DownloaderCommand {
     AddUrl(String),
+    /// Appends jobs to the queue, in order
+    AddJobs(Vec<Job>),
     Pause,
     Cancel,
     Resume,
+    /// Tries a job held after an error again
+    Retry,
+    /// Drops a job held after an error, keeping the error as its result
+    Skip,
     MoveDown(usize),
     MoveUp(usize),
     Delete(usize),
+    /// Moves a queued job to `position`, or to the end if that is past it
+    MoveJob { id: JobId, position: usize },
+    /// Moves a queued job right before another one
+    MoveBefore { id: JobId, before: JobId },
+    /// Removes a queued job. The running job is stopped with Cancel.
+    DeleteJob(JobId),
+    /// Replaces the URL and/or options of a queued job
+    EditJob {
+        id: JobId,
+        url: Option<Url>,
+        options: Option<JobOptions>,
+    },
+    /// Changes nothing. Answered in every state, to show that the
+    /// downloader is responsive.
+    Ping,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum CommandError {
+    /// No queued job with that id or index
+    NotFound,
+    /// The command does not apply to what the downloader is doing
+    InvalidState(&'static str),
+    /// A job that [`JobSpec::validate`](crate::job::JobSpec::validate) rejects
+    Invalid(String),
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::NotFound => write!(f, "no such job"),
+            CommandError::InvalidState(why) => write!(f, "{why}"),
+            CommandError::Invalid(why) => write!(f, "{why}"),
+        }
+    }
+}
+
+pub type CommandResult = Result<(), CommandError>;
+
+/// A command on its way to the downloader, with an optional channel for the
+/// outcome
+#[derive(Debug)]
+pub struct CommandRequest {
+    pub cmd: DownloaderCommand,
+    reply: Option<oneshot::Sender<CommandResult>>,
+}
+
+impl CommandRequest {
+    pub fn new(cmd: DownloaderCommand) -> (Self, oneshot::Receiver<CommandResult>) {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        (Self { cmd, reply: Some(reply_tx) }, reply_rx)
+    }
+    pub fn respond(self, result: CommandResult) {
+        if let Some(reply) = self.reply {
+            _ = reply.send(result);
+        }
+    }
+}
+
+impl From<DownloaderCommand> for CommandRequest {
+    /// A command nobody waits for
+    fn from(cmd: DownloaderCommand) -> Self {
+        Self { cmd, reply: None }
+    }
 }
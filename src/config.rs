@@ -0,0 +1,823 @@
+//! Command line and configuration file handling. Settings are layered:
+//! command line, then environment, then the configuration file, then the
+//! built in defaults.
+use crate::*;
+use crate::job::JobOptions;
+use crate::rate::EstimatorKind;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, str::FromStr};
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot, watch},
+};
+
+#[derive(clap::Parser, Debug)]
+pub struct Config {
+    /// Configuration file [default: $XDG_CONFIG_HOME/downd/config.toml]
+    #[clap(short = 'c', long = "config", env = "DOWND_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Print the effective configuration and exit
+    #[clap(long = "print-config")]
+    pub print_config: bool,
+    /// Port the web server listens on, replaces `web.listen` from the file
+    #[clap(short = 'p', long = "port", env = "DOWND_PORT", conflicts_with = "listen")]
+    port: Option<u16>,
+    /// Address the web server listens on, replaces `web.listen` from the file
+    #[clap(long = "bind", env = "DOWND_BIND", conflicts_with = "listen")]
+    bind: Option<SocketAddr>,
+    /// Addresses the web server listens on, instead of --bind: host:port,
+    /// [v6]:port or unix:PATH. May be repeated.
+    #[clap(long = "listen", env = "DOWND_LISTEN", value_delimiter = ',')]
+    listen: Vec<ListenAddr>,
+    /// PEM certificate chain, serves HTTPS on the TCP addresses
+    #[clap(long = "tls-cert", env = "DOWND_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[clap(long = "tls-key", env = "DOWND_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    #[clap(short = 's', long = "socket", env = "DOWND_SOCKET")]
+    socket: Option<std::path::PathBuf>,
+    /// Permissions of the control socket, in octal
+    #[clap(long = "socket-mode", env = "DOWND_SOCKET_MODE", value_parser = parse_mode)]
+    socket_mode: Option<u32>,
+    /// Group owning the control socket, by name or GID
+    #[clap(long = "socket-group", env = "DOWND_SOCKET_GROUP")]
+    socket_group: Option<String>,
+    /// UIDs allowed to query and control the daemon
+    #[clap(long = "allow-uid")]
+    allow_uid: Vec<u32>,
+    /// Groups allowed to query and control the daemon
+    #[clap(long = "allow-group")]
+    allow_group: Vec<String>,
+    /// UIDs allowed to query the daemon, but not to change anything
+    #[clap(long = "readonly-uid")]
+    readonly_uid: Vec<u32>,
+    /// Groups allowed to query the daemon, but not to change anything
+    #[clap(long = "readonly-group")]
+    readonly_group: Vec<String>,
+    #[clap(short = 'v', action = clap::ArgAction::Count)]
+    verbosity: u8,
+    /// error, warn, info, debug or trace. Overridden by -v.
+    #[clap(long = "log-level", env = "DOWND_LOG_LEVEL")]
+    log_level: Option<String>,
+    /// Path to yt-dlp
+    #[clap(long = "downloader", env = "DOWND_DOWNLOADER")]
+    downloader: Option<PathBuf>,
+    /// Directory finished downloads are moved to
+    #[clap(short = 'o', long = "output-dir", env = "DOWND_OUTPUT_DIR")]
+    output_dir: Option<PathBuf>,
+    /// Maximum download rate, e.g. 2M
+    #[clap(long = "rate-limit", env = "DOWND_RATE_LIMIT")]
+    rate_limit: Option<String>,
+    /// Seconds a running download may continue after SIGTERM before it is
+    /// stopped
+    #[clap(long = "shutdown-timeout", env = "DOWND_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout: Option<u64>,
+    /// Where the queue is kept across restarts
+    #[clap(long = "queue-file", env = "DOWND_QUEUE_FILE")]
+    queue_file: Option<PathBuf>,
+    /// SQLite database of finished downloads
+    #[clap(long = "history-file", env = "DOWND_HISTORY_FILE")]
+    history_file: Option<PathBuf>,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Terminal client for a running daemon
+    Tui,
+    /// Reads a password from stdin and prints its hash for `auth.users`
+    HashPassword,
+}
+
+/// The effective configuration, and the format of the configuration file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub downloader: DownloaderSettings,
+    pub web: WebSettings,
+    pub socket: SocketSettings,
+    pub progress: ProgressSettings,
+    pub log: LogSettings,
+    pub auth: AuthSettings,
+    /// Named job options, e.g. `[profiles.audio]` with `audio_only = true`
+    pub profiles: BTreeMap<String, JobOptions>,
+    pub history: HistorySettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloaderSettings {
+    pub binary: PathBuf,
+    /// Extra arguments passed to every download
+    pub args: Vec<String>,
+    /// Where finished downloads end up (yt-dlp's home path)
+    pub output_dir: Option<PathBuf>,
+    /// Where downloads are kept while in progress (yt-dlp's temp path)
+    pub temp_dir: Option<PathBuf>,
+    /// Passed to yt-dlp's --limit-rate
+    pub rate_limit: Option<String>,
+    /// Seconds without output before a download is reported as stuck
+    pub stuck_timeout_secs: u64,
+    /// Seconds a running download may continue after SIGTERM
+    pub shutdown_timeout_secs: u64,
+    pub queue_file: Option<PathBuf>,
+    /// Capacity of the internal update channel
+    pub update_buffer: usize,
+}
+
+impl Default for DownloaderSettings {
+    fn default() -> Self {
+        Self {
+            binary: "/usr/bin/yt-dlp".into(),
+            args: Vec::new(),
+            output_dir: None,
+            temp_dir: None,
+            rate_limit: None,
+            stuck_timeout_secs: 15,
+            shutdown_timeout_secs: 0,
+            queue_file: None,
+            update_buffer: 1024,
+        }
+    }
+}
+
+impl DownloaderSettings {
+    pub fn stuck_timeout(&self) -> Duration {
+        Duration::from_secs(self.stuck_timeout_secs)
+    }
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSettings {
+    pub bind: SocketAddr,
+    /// Number of rendered updates buffered for slow browsers
+    pub sse_buffer: usize,
+    /// Most page updates per second, changes in between are coalesced
+    pub max_frame_rate: u32,
+    /// Addresses to listen on. Replaces `bind` unless empty.
+    pub listen: Vec<ListenAddr>,
+    /// PEM certificate chain. TCP addresses serve HTTPS if set, unix
+    /// sockets stay plain for a reverse proxy. Reloaded when it changes.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Serve `downloader.output_dir` under `/files/`, to play or save
+    /// downloads from the browser. The directory is read at startup.
+    pub serve_files: bool,
+    /// Where the web server is reached from outside, e.g.
+    /// `https://nas.example`. Feeds link to it and are only served if set.
+    pub base_url: Option<String>,
+    /// Permissions of the unix sockets in `listen`, in octal
+    pub socket_mode: Option<String>,
+    pub socket_group: Option<String>,
+}
+
+/// An address for the web server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// `unix:PATH`, or any absolute path
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(ListenAddr::Unix(path.into()));
+        }
+        if s.starts_with('/') {
+            return Ok(ListenAddr::Unix(s.into()));
+        }
+        s.parse()
+            .map(ListenAddr::Tcp)
+            .map_err(|_| format!("Not an address or unix:PATH: {s}"))
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for ListenAddr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl WebSettings {
+    /// The configured addresses, or `bind`
+    pub fn listen_addrs(&self) -> Vec<ListenAddr> {
+        if self.listen.is_empty() {
+            vec![ListenAddr::Tcp(self.bind)]
+        } else {
+            self.listen.clone()
+        }
+    }
+    pub fn frame_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.max_frame_rate.max(1)
+    }
+    pub fn socket_mode(&self) -> Option<u32> {
+        self.socket_mode.as_deref().and_then(|m| parse_mode(m).ok())
+    }
+    /// `base_url` without a trailing slash
+    pub fn base_url(&self) -> Option<&str> {
+        self.base_url.as_deref().map(|url| url.trim_end_matches('/'))
+    }
+}
+
+impl Default for WebSettings {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            sse_buffer: 32,
+            max_frame_rate: 4,
+            listen: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            serve_files: false,
+            base_url: None,
+            socket_mode: None,
+            socket_group: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketSettings {
+    /// [default: $XDG_RUNTIME_DIR/downd]
+    pub path: Option<PathBuf>,
+    /// Permissions in octal, e.g. "660"
+    pub mode: Option<String>,
+    pub group: Option<String>,
+    pub allow_uids: Vec<u32>,
+    pub allow_groups: Vec<String>,
+    pub readonly_uids: Vec<u32>,
+    pub readonly_groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProgressSettings {
+    /// Minimum age of the samples before a rate is reported
+    pub rate_window_min_ms: u64,
+    /// Samples older than this are dropped from the rate
+    pub rate_window_max_ms: u64,
+    /// `sliding` over the window above, `ewma` or the `reported` speed
+    pub rate_estimator: EstimatorKind,
+    /// For `ewma`: after this long a report counts half as much
+    pub rate_half_life_ms: u64,
+    /// How often the rate is sampled for the chart
+    pub rate_history_interval_ms: u64,
+    /// How far back the chart goes
+    pub rate_history_secs: u64,
+}
+
+impl Default for ProgressSettings {
+    fn default() -> Self {
+        Self {
+            rate_window_min_ms: 1500,
+            rate_window_max_ms: 15000,
+            rate_estimator: EstimatorKind::Sliding,
+            rate_half_life_ms: 3000,
+            rate_history_interval_ms: 1000,
+            rate_history_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    pub level: String,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self { level: "error".into() }
+    }
+}
+
+/// The record of every download that ended
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistorySettings {
+    pub enabled: bool,
+    /// [default: $XDG_STATE_HOME/downd/history.sqlite]
+    pub path: Option<PathBuf>,
+    /// Lines of downloader output kept per download, for failures
+    pub log_lines: usize,
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: None,
+            log_lines: 50,
+        }
+    }
+}
+
+/// Who may use the web server. Without tokens or users it is open to anyone
+/// who can reach it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    /// Bearer tokens for scripts
+    pub tokens: Vec<TokenSettings>,
+    /// Accounts for basic auth and the login form
+    pub users: Vec<UserSettings>,
+    /// Hours a login form session stays valid
+    pub session_hours: u64,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            tokens: Vec::new(),
+            users: Vec::new(),
+            session_hours: 24 * 7,
+        }
+    }
+}
+
+impl AuthSettings {
+    pub fn enabled(&self) -> bool {
+        !self.tokens.is_empty() || !self.users.is_empty()
+    }
+    pub fn session_ttl(&self) -> Duration {
+        Duration::from_secs(self.session_hours * 3600)
+    }
+}
+
+/// What a client may do. `control` includes `read`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    #[default]
+    Read,
+    Control,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenSettings {
+    pub token: String,
+    #[serde(default)]
+    pub scope: Scope,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserSettings {
+    pub name: String,
+    /// Argon2 hash, see `downd hash-password`
+    pub password_hash: String,
+    #[serde(default)]
+    pub scope: Scope,
+}
+
+/// Shortest bearer token accepted
+const MIN_TOKEN_LEN: usize = 16;
+
+impl LogSettings {
+    pub fn level(&self) -> tracing::Level {
+        tracing::Level::from_str(&self.level).unwrap_or(tracing::Level::ERROR)
+    }
+}
+
+impl Settings {
+    /// Reads the configuration file named on the command line, or the
+    /// default one if it exists, and applies the command line on top
+    pub fn load(config: &Config) -> Anything<Self> {
+        let path = config.config.clone().or_else(default_config_path);
+        let mut settings = match &path {
+            Some(path) => match Self::from_file(path) {
+                Ok(settings) => settings,
+                // only a file that was asked for has to exist
+                Err(e) if config.config.is_none() && is_not_found(&*e) => Settings::default(),
+                Err(e) => return Err(format!("{}: {e}", path.display()).into()),
+            },
+            None => Settings::default(),
+        };
+        settings.apply(config);
+        settings.resolve();
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Anything<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    fn apply(&mut self, config: &Config) {
+        fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+            if let Some(v) = value {
+                *target = v.clone();
+            }
+        }
+        fn set_opt<T: Clone>(target: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                target.clone_from(value);
+            }
+        }
+        fn replace<T: Clone>(target: &mut Vec<T>, values: &[T]) {
+            if !values.is_empty() {
+                *target = values.to_vec();
+            }
+        }
+        set(&mut self.web.bind, &config.bind);
+        if let Some(port) = config.port {
+            self.web.bind.set_port(port);
+        }
+        // `listen` from the file would win over the address
+        if config.bind.is_some() || config.port.is_some() {
+            self.web.listen.clear();
+        }
+        replace(&mut self.web.listen, &config.listen);
+        set_opt(&mut self.web.tls_cert, &config.tls_cert);
+        set_opt(&mut self.web.tls_key, &config.tls_key);
+        set_opt(&mut self.socket.path, &config.socket);
+        set_opt(&mut self.socket.mode, &config.socket_mode.map(|m| format!("{m:o}")));
+        set_opt(&mut self.socket.group, &config.socket_group);
+        replace(&mut self.socket.allow_uids, &config.allow_uid);
+        replace(&mut self.socket.allow_groups, &config.allow_group);
+        replace(&mut self.socket.readonly_uids, &config.readonly_uid);
+        replace(&mut self.socket.readonly_groups, &config.readonly_group);
+        set(&mut self.log.level, &config.log_level);
+        match config.verbosity {
+            0 => {},
+            1 => self.log.level = "info".into(),
+            2 => self.log.level = "debug".into(),
+            _ => self.log.level = "trace".into(),
+        }
+        set(&mut self.downloader.binary, &config.downloader);
+        set_opt(&mut self.downloader.output_dir, &config.output_dir);
+        set_opt(&mut self.downloader.rate_limit, &config.rate_limit);
+        set(&mut self.downloader.shutdown_timeout_secs, &config.shutdown_timeout);
+        set_opt(&mut self.downloader.queue_file, &config.queue_file);
+        set_opt(&mut self.history.path, &config.history_file);
+    }
+
+    /// Fills in the defaults that depend on the environment
+    fn resolve(&mut self) {
+        if self.socket.path.is_none() {
+            self.socket.path = std::env::var_os("XDG_RUNTIME_DIR")
+                .map(|d| PathBuf::from(d).join("downd"));
+        }
+        if self.downloader.queue_file.is_none() {
+            self.downloader.queue_file = state_dir().map(|d| d.join("queue"));
+        }
+        if self.history.path.is_none() {
+            self.history.path = state_dir().map(|d| d.join("history.sqlite"));
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for mode in [&self.socket.mode, &self.web.socket_mode].into_iter().flatten() {
+            parse_mode(mode)?;
+        }
+        if tracing::Level::from_str(&self.log.level).is_err() {
+            return Err(format!("Unknown log level: {}", self.log.level));
+        }
+        if self.downloader.update_buffer == 0 || self.web.sse_buffer == 0 {
+            return Err("Channel buffers must not be empty".into());
+        }
+        if self.web.tls_cert.is_some() != self.web.tls_key.is_some() {
+            return Err("tls_cert and tls_key must be set together".into());
+        }
+        if self.web.max_frame_rate == 0 {
+            return Err("max_frame_rate must be at least 1".into());
+        }
+        if let Some(url) = &self.web.base_url {
+            match url.parse::<warp::http::Uri>() {
+                Ok(uri) if matches!(uri.scheme_str(), Some("http" | "https")) && uri.query().is_none() => {}
+                _ => return Err(format!("base_url is not an http or https URL: {url}")),
+            }
+        }
+        if self.progress.rate_window_min_ms >= self.progress.rate_window_max_ms {
+            return Err("rate_window_min_ms must be less than rate_window_max_ms".into());
+        }
+        if self.progress.rate_half_life_ms == 0 {
+            return Err("rate_half_life_ms must be at least 1".into());
+        }
+        if self.progress.rate_history_interval_ms == 0 {
+            return Err("rate_history_interval_ms must be at least 1".into());
+        }
+        for token in &self.auth.tokens {
+            if token.token.len() < MIN_TOKEN_LEN {
+                return Err(format!("Tokens must be at least {MIN_TOKEN_LEN} characters"));
+            }
+        }
+        for (i, user) in self.auth.users.iter().enumerate() {
+            if user.name.is_empty() || user.name.contains(':') {
+                return Err(format!("Invalid user name: {:?}", user.name));
+            }
+            if self.auth.users[..i].iter().any(|u| u.name == user.name) {
+                return Err(format!("Duplicate user: {}", user.name));
+            }
+            if argon2::password_hash::phc::PasswordHash::new(&user.password_hash).is_err() {
+                return Err(format!("Not a password hash for user {}", user.name));
+            }
+        }
+        for (name, options) in &self.profiles {
+            options.validate().map_err(|e| format!("Profile {name}: {e}"))?;
+        }
+        if self.auth.session_hours == 0 {
+            return Err("session_hours must be at least 1".into());
+        }
+        Ok(())
+    }
+
+    pub fn socket_path(&self) -> Result<&Path, String> {
+        self.socket.path.as_deref().ok_or_else(|| {
+            error!("Socket path must be specified");
+            "No socket path".into()
+        })
+    }
+
+    pub fn socket_mode(&self) -> Option<u32> {
+        self.socket.mode.as_deref().and_then(|m| parse_mode(m).ok())
+    }
+}
+
+/// Settings that are only read at startup
+const RESTART_REQUIRED: [&str; 9] = [
+    "web.",
+    "auth.",
+    "profiles.",
+    "history.",
+    "socket.",
+    "progress.",
+    "log.",
+    "downloader.update_buffer",
+    "downloader.queue_file",
+];
+
+/// Settings whose values are never shown in a diff
+const SECRET: [&str; 2] = ["auth.tokens", "auth.users"];
+
+/// Describes every setting that differs between the two configurations
+pub fn diff(old: &Settings, new: &Settings) -> Vec<String> {
+    let (old, new) = (flatten(old), flatten(new));
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .filter(|k| old.get(*k) != new.get(*k))
+        .map(|k| {
+            let unset = "unset".to_string();
+            // changes are reported to clients, keep credentials out
+            let mut line = if SECRET.contains(&k.as_str()) {
+                format!("{k}: changed")
+            } else {
+                format!("{k}: {} -> {}", old.get(k).unwrap_or(&unset), new.get(k).unwrap_or(&unset))
+            };
+            if RESTART_REQUIRED.iter().any(|p| k.starts_with(p)) {
+                line += " (restart required)";
+            }
+            line
+        })
+        .collect()
+}
+
+/// Maps "section.key" to the value as it would appear in the file
+fn flatten(settings: &Settings) -> BTreeMap<String, String> {
+    let mut map = BTreeMap::new();
+    if let Ok(toml::Value::Table(sections)) = toml::Value::try_from(settings) {
+        for (section, table) in sections {
+            let toml::Value::Table(table) = table else { continue };
+            for (key, value) in table {
+                map.insert(format!("{section}.{key}"), value.to_string());
+            }
+        }
+    }
+    map
+}
+
+/// Result of a reload: the changes made, or why the new file was rejected
+pub type ReloadReply = Result<Vec<String>, String>;
+
+/// Re-reads the configuration on SIGHUP or on request from the control
+/// socket. Invalid files are rejected and the current settings stay in place.
+pub async fn reloader(
+    config: Config,
+    settings_tx: watch::Sender<Settings>,
+    mut reload_rx: mpsc::UnboundedReceiver<oneshot::Sender<ReloadReply>>,
+    update_tx: broadcast::Sender<DownloaderMsg>,
+) -> std::io::Result<()> {
+    let mut hup = signal(SignalKind::hangup())?;
+    loop {
+        let reply = select! {
+            _ = hup.recv() => {
+                info!("SIGHUP received, reloading configuration");
+                None
+            },
+            Some(reply) = reload_rx.recv() => Some(reply),
+        };
+        systemd::notify("RELOADING=1");
+        let result = match Settings::load(&config) {
+            Ok(new) => {
+                let changes = diff(&settings_tx.borrow(), &new);
+                info!("Configuration reloaded, {} changes", changes.len());
+                for change in &changes {
+                    info!("  {change}");
+                }
+                settings_tx.send_replace(new);
+                _ = update_tx.send(DownloaderMsg::ConfigReloaded(changes.clone()));
+                Ok(changes)
+            }
+            Err(e) => {
+                error!("Configuration not reloaded: {e}");
+                Err(e.to_string())
+            }
+        };
+        systemd::notify("READY=1");
+        if let Some(reply) = reply {
+            _ = reply.send(result);
+        }
+    }
+}
+
+fn is_not_found(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(d) => PathBuf::from(d),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("downd").join("config.toml"))
+}
+
+fn state_dir() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(d) => PathBuf::from(d),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(dir.join("downd"))
+}
+
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8).map_err(|e| format!("not an octal mode: {e}"))
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn check_layering() {
+        let file: Settings = toml::from_str(
+            r#"
+            [downloader]
+            binary = "/opt/yt-dlp"
+            args = ["--limit-rate", "1M"]
+            [web]
+            bind = "0.0.0.0:8080"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.downloader.args, ["--limit-rate", "1M"]);
+        assert_eq!(file.downloader.stuck_timeout_secs, 15);
+
+        let mut settings = file.clone();
+        let cli = Config::parse_from(["downd", "-p", "9000", "--downloader", "/bin/true"]);
+        settings.apply(&cli);
+        assert_eq!(settings.web.bind, "0.0.0.0:9000".parse().unwrap());
+        assert_eq!(settings.downloader.binary, PathBuf::from("/bin/true"));
+        assert_eq!(settings.downloader.args, file.downloader.args);
+    }
+
+    #[test]
+    fn check_listen() {
+        let file: Settings = toml::from_str(
+            r#"
+            [web]
+            listen = ["[::1]:3000", "unix:/run/downd/http"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            file.web.listen_addrs(),
+            [
+                ListenAddr::Tcp("[::1]:3000".parse().unwrap()),
+                ListenAddr::Unix("/run/downd/http".into()),
+            ]
+        );
+        assert!(toml::from_str::<Settings>("[web]\nlisten = [\"localhost\"]").is_err());
+        let cli = Config::parse_from(["downd", "--listen", "0.0.0.0:80,/tmp/http"]);
+        let mut settings = Settings::default();
+        settings.apply(&cli);
+        assert_eq!(settings.web.listen[1], ListenAddr::Unix("/tmp/http".into()));
+        settings.apply(&Config::parse_from(["downd", "-p", "8080"]));
+        assert_eq!(settings.web.listen_addrs(), [ListenAddr::Tcp("127.0.0.1:8080".parse().unwrap())]);
+        assert!(Config::try_parse_from(["downd", "-p", "8080", "--listen", "/tmp/http"]).is_err());
+        assert_eq!(Settings::default().web.listen_addrs(), [ListenAddr::Tcp(Settings::default().web.bind)]);
+        let mut settings = Settings::default();
+        settings.web.base_url = Some("https://nas.example/downd/".into());
+        assert_eq!(settings.validate(), Ok(()));
+        assert_eq!(settings.web.base_url(), Some("https://nas.example/downd"));
+        settings.web.base_url = Some("nas.example".into());
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn check_unknown_keys() {
+        assert!(toml::from_str::<Settings>("[web]\nport = 3000").is_err());
+    }
+
+    #[test]
+    fn check_diff() {
+        let old = Settings::default();
+        let mut new = old.clone();
+        new.downloader.rate_limit = Some("1M".into());
+        new.downloader.stuck_timeout_secs = 60;
+        new.web.bind.set_port(8080);
+        new.auth.tokens.push(TokenSettings { token: "0123456789abcdef".into(), scope: Scope::Control });
+        assert_eq!(
+            diff(&old, &new),
+            [
+                "auth.tokens: changed (restart required)",
+                "downloader.rate_limit: unset -> \"1M\"",
+                "downloader.stuck_timeout_secs: 15 -> 60",
+                "web.bind: \"127.0.0.1:3000\" -> \"127.0.0.1:8080\" (restart required)",
+            ]
+        );
+        assert!(diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn check_auth() {
+        let hash = "$argon2id$v=19$m=19456,t=2,p=1$OCXPYkiKXxRoZPCVsz2n+A$sXW70gxuZgSA8MOoADypl/GPvr/2ekNygZfr3uw6u6k";
+        let file: Settings = toml::from_str(&format!(
+            r#"
+            [[auth.tokens]]
+            token = "0123456789abcdef"
+            [[auth.users]]
+            name = "admin"
+            password_hash = "{hash}"
+            scope = "control"
+            "#
+        ))
+        .unwrap();
+        assert!(file.auth.enabled());
+        assert_eq!(file.auth.tokens[0].scope, Scope::Read);
+        assert!(Scope::Control > Scope::Read);
+        assert!(file.validate().is_ok());
+        let mut bad = file.clone();
+        bad.auth.users[0].password_hash = "hunter2".into();
+        assert!(bad.validate().is_err());
+        let mut bad = file.clone();
+        bad.auth.users.push(bad.auth.users[0].clone());
+        assert!(bad.validate().is_err());
+        let mut bad = file;
+        bad.auth.tokens[0].token = "short".into();
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn check_profiles() {
+        let file: Settings = toml::from_str(
+            r#"
+            [profiles.audio]
+            audio_only = true
+            subdir = "music"
+            "#,
+        )
+        .unwrap();
+        assert!(file.profiles["audio"].audio_only);
+        assert!(file.validate().is_ok());
+        assert!(toml::from_str::<Settings>("[profiles.bad]\nsubdir = \"../x\"").unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn check_roundtrip() {
+        let settings = Settings::default();
+        let text = toml::to_string_pretty(&settings).unwrap();
+        assert_eq!(toml::from_str::<Settings>(&text).unwrap(), settings);
+    }
+}
@@ -9,8 +9,20 @@ use tokio::{
 use tokio_stream::{wrappers::LinesStream, Stream, StreamExt};
 
 use crate::*;
+use crate::systemd::Watchdog;
+use crate::config::DownloaderSettings;
+use crate::commands::{CommandError, CommandRequest, CommandResult};
+use crate::job::{validate_url, Job, JobOptions, JobSpec};
+use crate::history::Status;
+use tokio::sync::watch;
 
-const STUCK_DURATION: Duration = Duration::from_secs(15);
+/// How long a downloader gets to exit after SIGTERM before it is killed
+const TERMINATE_GRACE: Duration = Duration::from_secs(5);
+/// Most output lines passed on per second. A downloader that floods its
+/// output would push the progress out of slow subscribers' buffers.
+const OUTPUT_LINES_PER_SEC: u32 = 20;
+/// How a job the user paused is reported when it is skipped
+const SKIPPED: (Status, &str) = (Status::Skipped, "Skipped");
 
 #[derive(Debug)]
 /// The reason why the downloader process terminated
@@ -30,11 +42,23 @@ pub enum ExitReason {
     ExternalSignal,
     /// The task that monitors the downloader process ended improperly (panic)
     Panic,
+    /// The daemon is shutting down. If a download was interrupted, its URL
+    /// is back at the head of the queue.
+    Shutdown,
 }
 
 impl std::fmt::Display for ExitReason {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self}")
+        match self {
+            ExitReason::Finished => write!(f, "Finished"),
+            ExitReason::ExitCode(code) => write!(f, "Exit code {code}"),
+            ExitReason::Cancelled => write!(f, "Cancelled"),
+            ExitReason::Paused => write!(f, "Paused"),
+            ExitReason::IOError(e) => write!(f, "IO error: {e}"),
+            ExitReason::ExternalSignal => write!(f, "Killed by signal"),
+            ExitReason::Panic => write!(f, "Panic"),
+            ExitReason::Shutdown => write!(f, "Shutdown"),
+        }
     }
 }
 
@@ -53,17 +77,16 @@ impl<T: Default> From<ExitReason> for Result<T, ExitReason> {
 
 /// Spawns the given command and returns newline separated String streams for
 /// stdout and stderr
-pub fn spawn_downloader_command<'a>(
+pub fn spawn_downloader_command(
     mut cmd: Command,
-) -> (
+) -> std::io::Result<(
     Child,
     impl Stream<Item = tokio::io::Result<String>>,
-) {
+)> {
     let mut child = cmd
         .stdout(std::process::Stdio::piped())
         .stderr(std::process::Stdio::piped())
-        .spawn()
-        .expect("Malformed command");
+        .spawn()?;
     let out = child.stdout.take().expect("!!!");
     let err = child.stderr.take().expect("!!!");
     let out = BufReader::new(out).lines();
@@ -71,225 +94,523 @@ pub fn spawn_downloader_command<'a>(
     let out = LinesStream::new(out);
     let err = LinesStream::new(err);
     let st = out.merge(err);
-    (child, st)
+    Ok((child, st))
 }
 
-fn handle_queue_commands(q: &mut AsyncQueue<Url>, cmd: &DownloaderCommand, update_tx: &broadcast::Sender<DownloaderMsg>) {
-    match cmd {
-        DownloaderCommand::AddUrl(url) => {
-            q.push(url);
+/// Applies a command that only touches the queue
+fn handle_queue_commands(q: &mut AsyncQueue<Job>, cmd: &DownloaderCommand, update_tx: &broadcast::Sender<DownloaderMsg>) -> CommandResult {
+    use DownloaderCommand::*;
+    let found = match cmd {
+        // jobs from HTTP and the socket are only checked here
+        AddUrl(url) => {
+            let spec = JobSpec { url: url.clone(), options: JobOptions::default() };
+            spec.validate().map_err(CommandError::Invalid)?;
+            q.push(Job::from(spec));
+            true
         }
-        DownloaderCommand::MoveDown(index) => {
-            q.move_down(*index);
-        }
-        DownloaderCommand::MoveUp(index) => {
-            q.move_up(*index);
+        AddJobs(jobs) => {
+            for (i, job) in jobs.iter().enumerate() {
+                job.spec().validate().map_err(|e| match jobs.len() {
+                    1 => CommandError::Invalid(e),
+                    _ => CommandError::Invalid(format!("job {i}: {e}")),
+                })?;
+            }
+            for job in jobs {
+                q.push(job.clone());
+            }
+            true
         }
-        DownloaderCommand::Delete(index) => {
-            q.remove(*index);
+        MoveDown(index) => q.move_down(*index),
+        MoveUp(index) => q.move_up(*index),
+        Delete(index) => q.remove(*index).is_some(),
+        MoveJob { id, position } => q.position(*id).is_some_and(|i| q.move_to(i, *position)),
+        MoveBefore { id, before } => match (q.position(*id), q.position(*before)) {
+            (Some(i), Some(target)) => q.move_before(i, target),
+            _ => false,
+        },
+        DeleteJob(id) => q.position(*id).and_then(|i| q.remove(i)).is_some(),
+        EditJob { id, url, options } => {
+            if let Some(url) = url {
+                validate_url(url).map_err(CommandError::Invalid)?;
+            }
+            if let Some(options) = options {
+                options.validate().map_err(CommandError::Invalid)?;
+            }
+            match q.get_mut(*id) {
+                Some(job) => {
+                    if let Some(url) = url {
+                        job.url = url.clone();
+                    }
+                    if let Some(options) = options {
+                        job.options = options.clone();
+                    }
+                    true
+                }
+                None => false,
+            }
         }
-        _ => return,
+        Ping | Pause | Cancel | Resume | Retry | Skip => return Err(CommandError::InvalidState("not a queue command")),
+    };
+    if !found {
+        return Err(CommandError::NotFound);
     }
     update_tx.send(DownloaderMsg::QueueUpdate(q.contents()));
+    Ok(())
+}
+
+/// What the downloader is doing right now, for diagnosing hangs
+#[derive(Debug, Clone, Default)]
+pub struct Internals {
+    pub phase: &'static str,
+    /// Of the running downloader process
+    pub pid: Option<u32>,
+    /// When the running download counts as stuck, unless it writes more
+    pub stuck_at: Option<Instant>,
+    /// When the running download is stopped for a shutdown
+    pub stop_at: Option<Instant>,
+}
+
+/// Channels and settings shared by all states of the downloader
+pub struct Ctx {
+    pub cmd_rx: UnboundedReceiver<CommandRequest>,
+    pub update_tx: broadcast::Sender<DownloaderMsg>,
+    pub internals_tx: watch::Sender<Internals>,
+    pub watchdog: Watchdog,
+    pub shutdown_rx: watch::Receiver<bool>,
+    pub settings_rx: watch::Receiver<Settings>,
+    /// Snapshot of the settings, taken when a download starts
+    pub settings: DownloaderSettings,
+    /// As last reported
+    pub state: DaemonState,
+}
+
+impl Ctx {
+    fn shutting_down(&self) -> bool {
+        *self.shutdown_rx.borrow()
+    }
+    /// Starts reporting a new phase, with no timers running
+    fn enter(&self, phase: &'static str) {
+        self.internals_tx.send_replace(Internals { phase, ..Internals::default() });
+    }
+    /// Reports the state, unless it stays the same
+    fn set_state(&mut self, state: DaemonState) {
+        if self.state != state {
+            self.state = state.clone();
+            self.update_tx.send(DownloaderMsg::StateChanged(state));
+        }
+    }
+    fn hold(&mut self, reason: HoldReason) {
+        self.update_tx.send(DownloaderMsg::Hold(reason.clone()));
+        self.set_state(DaemonState::Held { reason });
+    }
+    fn idle(&mut self) {
+        self.update_tx.send(DownloaderMsg::Idle);
+        self.set_state(DaemonState::Idle);
+    }
+    /// Picks up a reloaded configuration
+    fn refresh_settings(&mut self) {
+        self.settings = self.settings_rx.borrow_and_update().downloader.clone();
+    }
+}
+
+/// Drops the current job, reporting it as finished with `status`
+fn drop_job(job: &mut Option<Job>, status: Status, reason: String, update_tx: &broadcast::Sender<DownloaderMsg>) -> CommandResult {
+    let Some(job) = job.take() else {
+        return Err(CommandError::InvalidState("no current job"));
+    };
+    update_tx.send(DownloaderMsg::Finished { job, status, reason });
+    Ok(())
 }
 
+/// Waits for the user. A paused job goes on with Resume, a failed one is only
+/// tried again on Retry. Cancel drops the job and keeps holding. Skip drops it
+/// and moves on to the next job, reporting it as `skipped`: failed with the
+/// error that caused the hold, or skipped by the user. Resume after an error
+/// moves on like Skip.
 async fn run_hold(
-    q: &mut AsyncQueue<Url>,
-    url: &mut Option<Url>,
-    cmd_rx: &mut UnboundedReceiver<DownloaderCommand>,
-    update_tx: &broadcast::Sender<DownloaderMsg>,
-) {
+    q: &mut AsyncQueue<Job>,
+    job: &mut Option<Job>,
+    skipped: (Status, &str),
+    ctx: &mut Ctx,
+) -> Result<(), ExitReason> {
     info!("Holding for user input");
+    ctx.enter("holding");
     loop {
-        let cmd = cmd_rx.recv().await;
-        if let Some(cmd) = cmd {
-            debug!("Command received: {cmd:?}");
+        let cmd = select! {
+            cmd = ctx.cmd_rx.recv() => cmd,
+            _ = shutdown::requested(&mut ctx.shutdown_rx) => return Err(ExitReason::Shutdown),
+            _ = ctx.watchdog.ping() => continue,
+        };
+        if let Some(req) = cmd {
+            debug!("Command received: {:?}", req.cmd);
             use DownloaderCommand::*;
-            match cmd {
+            match req.cmd {
                 Resume => {
                     debug!("resume received while holding");
+                    if skipped.0 == Status::Failed {
+                        _ = drop_job(job, skipped.0, skipped.1.to_string(), &ctx.update_tx);
+                    }
+                    req.respond(Ok(()));
+                    break
+                },
+                Retry if job.is_some() => {
+                    req.respond(Ok(()));
                     break
                 },
+                Retry => req.respond(Err(CommandError::InvalidState("nothing to retry"))),
                 Cancel => {
-                    // TODO: broadcast the cancellation
-                    *url = None;
+                    let result = drop_job(job, Status::Cancelled, ExitReason::Cancelled.to_string(), &ctx.update_tx);
+                    req.respond(result);
+                }
+                Skip => {
+                    let result = drop_job(job, skipped.0, skipped.1.to_string(), &ctx.update_tx);
+                    let skipped = result.is_ok();
+                    req.respond(result);
+                    if skipped {
+                        break
+                    }
+                }
+                Pause => req.respond(Err(CommandError::InvalidState("already paused"))),
+                Ping => req.respond(Ok(())),
+                _ => {
+                    let result = handle_queue_commands(q, &req.cmd, &ctx.update_tx);
+                    req.respond(result);
                 }
-                _ => handle_queue_commands(q, &cmd, update_tx),
             }
         } else {
             panic!("command channel dropped");
         }
     }
+    Ok(())
 }
 
 async fn run_idle(
-    q: &mut AsyncQueue<Url>,
-    current_url: &mut Option<Url>,
-    cmd_rx: &mut UnboundedReceiver<DownloaderCommand>,
-    update_tx: &broadcast::Sender<DownloaderMsg>,
-) {
+    q: &mut AsyncQueue<Job>,
+    current_job: &mut Option<Job>,
+    ctx: &mut Ctx,
+) -> Result<(), ExitReason> {
     info!("In idle");
-    update_tx.send(DownloaderMsg::Idle);
+    ctx.idle();
+    ctx.enter("idle");
     loop {
         select! {
-            url = q.next() => {
-                if let Some(url) = url {
-                    *current_url = Some(url);
+            job = q.next() => {
+                if let Some(job) = job {
+                    *current_job = Some(job);
                     break;
                 } else {
                     debug!("queue is empty");
                 }
             },
-            cmd = cmd_rx.recv() => {
-                if let Some(cmd) = cmd {
-                    debug!("Command received: {cmd:?}");
-                    handle_queue_commands(q, &cmd, update_tx);
-                    if matches!(cmd, DownloaderCommand::Pause) {
-                        update_tx.send(DownloaderMsg::Hold("User hold".into()));
-                        _ = run_hold(q, current_url, cmd_rx, update_tx).await;
-                        update_tx.send(DownloaderMsg::Idle);
+            cmd = ctx.cmd_rx.recv() => {
+                if let Some(req) = cmd {
+                    debug!("Command received: {:?}", req.cmd);
+                    match req.cmd {
+                        DownloaderCommand::Pause => {
+                            req.respond(Ok(()));
+                            ctx.hold(HoldReason::User);
+                            run_hold(q, current_job, SKIPPED, ctx).await?;
+                            ctx.idle();
+                            ctx.enter("idle");
+                        }
+                        DownloaderCommand::Resume => req.respond(Err(CommandError::InvalidState("not paused"))),
+                        DownloaderCommand::Cancel => req.respond(Err(CommandError::InvalidState("nothing to cancel"))),
+                        DownloaderCommand::Retry => req.respond(Err(CommandError::InvalidState("nothing to retry"))),
+                        DownloaderCommand::Skip => req.respond(Err(CommandError::InvalidState("nothing to skip"))),
+                        DownloaderCommand::Ping => req.respond(Ok(())),
+                        _ => {
+                            let result = handle_queue_commands(q, &req.cmd, &ctx.update_tx);
+                            req.respond(result);
+                        }
                     }
                 } else {
                     panic!("command channel dropped");
                 }
             },
+            _ = shutdown::requested(&mut ctx.shutdown_rx) => return Err(ExitReason::Shutdown),
+            _ = ctx.watchdog.ping() => {},
             else => {
                 panic!("nothing else to do in idle");
             },
         }
     }
+    Ok(())
 }
 
 fn start_downloader_test_process(
     url: impl AsRef<std::ffi::OsStr>,
-) -> (
+) -> std::io::Result<(
     Child,
     impl Stream<Item = Result<String, std::io::Error>>,
-) {
+)> {
     debug!(
         "Constructing command for url: {}",
         url.as_ref().to_string_lossy()
     );
     let mut cmd = Command::new("/usr/bin/cat");
     cmd.arg(&url);
-    let (child, st) = spawn_downloader_command(cmd);
+    let (child, st) = spawn_downloader_command(cmd)?;
     let st = DelayedStream::new(st, Duration::from_millis(250));
-    (child, st)
+    Ok((child, st))
 }
 
 fn start_downloader_process(
-    url: impl AsRef<std::ffi::OsStr>,
-) -> (
+    settings: &DownloaderSettings,
+    job: &Job,
+) -> std::io::Result<(
     Child,
     impl Stream<Item = Result<String, std::io::Error>>,
-) {
-    let mut cmd = ytdlp_command(&url);
-    cmd.arg(url);
-    spawn_downloader_command(cmd)
+)> {
+    spawn_downloader_command(ytdlp_command(settings, job))
 }
 
 async fn main_inner_loop(
-    q: &mut AsyncQueue<Url>,
-    cmd_rx: &mut UnboundedReceiver<DownloaderCommand>,
-    update_tx: &broadcast::Sender<DownloaderMsg>,
-) -> ExitReason {
-    let mut current_url: Option<Url> = None;
+    q: &mut AsyncQueue<Job>,
+    ctx: &mut Ctx,
+) -> Result<(), ExitReason> {
+    let mut current_job: Option<Job> = None;
+    let result = downloader_loop(q, &mut current_job, ctx).await;
+    if let Err(ExitReason::Shutdown) = result {
+        ctx.set_state(DaemonState::ShuttingDown);
+    }
+    // an interrupted download is the first to resume after a restart
+    if let Some(job) = current_job {
+        q.push_front(job);
+    }
+    result
+}
+
+async fn downloader_loop(
+    q: &mut AsyncQueue<Job>,
+    current_job: &mut Option<Job>,
+    ctx: &mut Ctx,
+) -> Result<(), ExitReason> {
     loop {
-        run_idle(q, &mut current_url, cmd_rx, update_tx).await;
-        update_tx.send(DownloaderMsg::QueueUpdate(q.contents()));
+        run_idle(q, current_job, ctx).await?;
+        ctx.update_tx.send(DownloaderMsg::QueueUpdate(q.contents()));
+        if ctx.shutting_down() {
+            return Err(ExitReason::Shutdown);
+        }
 
         // downloader loop
-        while let Some(url) = &current_url {
+        let mut spawned = 0;
+        if let Some(job) = current_job {
+            ctx.update_tx.send(DownloaderMsg::Current(job.clone()));
+        }
+        while let Some(job) = current_job.clone() {
+            ctx.refresh_settings();
             // TODO: remove test code
-            let (child, st) = start_downloader_process(&url);
-            // let (child, st) = start_downloader_test_process(&url);
-
-            let exitreason = handle_downloader(q, cmd_rx, child, st, update_tx).await;
+            let exitreason = match start_downloader_process(&ctx.settings, &job) {
+            // let exitreason = match start_downloader_test_process(&job.url) {
+                Ok((child, st)) => {
+                    spawned += 1;
+                    ctx.update_tx.send(DownloaderMsg::Spawned { attempt: spawned });
+                    handle_downloader(q, child, st, ctx).await
+                }
+                Err(e) => ExitReason::IOError(e),
+            };
             info!("transition from downloading is {exitreason:?}");
+            // failed and paused jobs are held, and only finished once the
+            // user skips them
+            let reason = exitreason.to_string();
+            let status = match exitreason {
+                ExitReason::Finished => Some(Status::Finished),
+                ExitReason::Cancelled => Some(Status::Cancelled),
+                _ => None,
+            };
+            if let Some(status) = status {
+                ctx.update_tx.send(DownloaderMsg::Finished {
+                    job: job.clone(),
+                    status,
+                    reason: reason.clone(),
+                });
+            }
             use ExitReason::*;
             match exitreason {
-                Finished => break,
+                Finished => {
+                    *current_job = None;
+                }
                 ExitCode(e) => {
                     error!("Downloader exited with error code {e}");
-                    update_tx.send(DownloaderMsg::Hold(format!("Error code {e}")));
-                    run_hold(q, &mut current_url, cmd_rx, update_tx).await;
-                    break;
+                    ctx.hold(HoldReason::ExitCode { code: e });
+                    run_hold(q, current_job, (Status::Failed, &reason), ctx).await?;
                 }
                 Cancelled => {
                     debug!("Download cancelled by user");
-                    break;
+                    *current_job = None;
                 }
                 Paused => {
-                    update_tx.send(DownloaderMsg::Hold("User hold".into()));
-                    run_hold(q, &mut current_url, cmd_rx, update_tx).await;
+                    ctx.hold(HoldReason::User);
+                    run_hold(q, current_job, SKIPPED, ctx).await?;
                 }
                 IOError(e) => {
                     error!("Error: {e:?}");
-                    update_tx.send(DownloaderMsg::Hold("IO Error!".into()));
-                    run_hold(q, &mut current_url, cmd_rx, update_tx).await;
-                    break;
+                    ctx.hold(HoldReason::Io { error: e.to_string() });
+                    run_hold(q, current_job, (Status::Failed, &reason), ctx).await?;
                 }
                 ExternalSignal => {
                     error!("Downloader killed via external signal");
-                    update_tx.send(DownloaderMsg::Hold("Downloader killed".into()));
-                    run_hold(q, &mut current_url, cmd_rx, update_tx).await;
-                    break;
+                    ctx.hold(HoldReason::Killed);
+                    run_hold(q, current_job, (Status::Failed, &reason), ctx).await?;
                 }
+                Shutdown => return Err(Shutdown),
                 Panic => todo!(),
             }
+            if ctx.shutting_down() {
+                return Err(Shutdown);
+            }
         } // downloader loop
     } // inner loop
 }
 
 pub async fn main_outer_loop(
-    mut cmd_rx: UnboundedReceiver<DownloaderCommand>,
-    // update_tx: broadcast::Sender<DownloaderMsg>,
+    cmd_rx: UnboundedReceiver<CommandRequest>,
     update_tx: broadcast::Sender<DownloaderMsg>,
+    internals_tx: watch::Sender<Internals>,
+    shutdown_rx: watch::Receiver<bool>,
+    mut settings_rx: watch::Receiver<Settings>,
 ) {
-    let mut q = AsyncQueue::<Url>::new();
+    let settings = settings_rx.borrow_and_update().downloader.clone();
+    let queue_file = settings.queue_file.clone();
+    let mut q = AsyncQueue::<Job>::new();
+    if let Some(path) = &queue_file {
+        match AsyncQueue::load(path).await {
+            Ok(saved) => {
+                info!("Restored {} queued URLs from {path:?}", saved.len());
+                q = saved;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => error!("Could not read queue file {path:?}: {e}"),
+        }
+    }
+    update_tx.send(DownloaderMsg::QueueUpdate(q.contents()));
+    let mut ctx = Ctx {
+        cmd_rx,
+        update_tx,
+        internals_tx,
+        watchdog: Watchdog::from_env(),
+        shutdown_rx,
+        settings_rx,
+        settings,
+        state: DaemonState::Idle,
+    };
     info!("Entering main outer loop");
-    loop {
-        let trans = main_inner_loop(&mut q, &mut cmd_rx, &update_tx).await;
-        info!("Inner loop exited with {trans:?}");
-        break;
+    let trans = main_inner_loop(&mut q, &mut ctx).await;
+    info!("Inner loop exited with {trans:?}");
+    if let Some(path) = &queue_file {
+        match q.save(path).await {
+            Ok(()) => info!("Saved {} queued URLs to {path:?}", q.len()),
+            Err(e) => error!("Could not write queue file {path:?}: {e}"),
+        }
     }
 }
 
-fn handle_line(line: tokio::io::Result<String>, chan: &broadcast::Sender<DownloaderMsg>) {
-    // ignore lines that we can't parse
-    if let Ok(x) = line {
-        if let Ok(msg) = DownloaderMsg::try_from(x) {
-            _ = chan.send(msg);
+/// Counts the output lines of the current second and drops the ones over
+/// the limit
+#[derive(Debug)]
+struct OutputLimit {
+    since: Instant,
+    sent: u32,
+    dropped: u64,
+}
+
+impl OutputLimit {
+    fn new(now: Instant) -> Self {
+        Self { since: now, sent: 0, dropped: 0 }
+    }
+    fn allow(&mut self, now: Instant) -> bool {
+        if now.saturating_duration_since(self.since) >= Duration::from_secs(1) {
+            if self.dropped > 0 {
+                warn!("Dropped {} lines of downloader output", self.dropped);
+            }
+            *self = Self::new(now);
+        }
+        if self.sent < OUTPUT_LINES_PER_SEC {
+            self.sent += 1;
+            true
+        } else {
+            self.dropped += 1;
+            false
         }
     }
 }
 
+/// Passes a line on, returning the state it shows the download is in
+fn handle_line(
+    line: tokio::io::Result<String>,
+    chan: &broadcast::Sender<DownloaderMsg>,
+    limit: &mut OutputLimit,
+) -> Option<DaemonState> {
+    // lines we can't parse are passed on as they are, they explain failures
+    let x = line.ok()?;
+    let msg = DownloaderMsg::try_from(x.clone()).unwrap_or(DownloaderMsg::Output(x));
+    use DownloaderMsg::*;
+    let state = match msg {
+        Starting(_) => Some(DaemonState::Starting),
+        Downloading { .. } => Some(DaemonState::Downloading),
+        PostProcessing | Moved(_) | Saved(_) | Info(_) => Some(DaemonState::PostProcessing),
+        _ => None,
+    };
+    if !matches!(msg, Output(_)) || limit.allow(Instant::now()) {
+        _ = chan.send(msg);
+    }
+    state
+}
+
+/// Asks the downloader to exit, giving it a chance to clean up
+fn terminate(child: &Child) {
+    if let Some(pid) = child.id() {
+        // SAFETY: plain kill(2) on the pid of our own child process
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+    }
+}
+
 async fn handle_downloader(
-    q: &mut AsyncQueue<Url>,
-    cmd_rx: &mut UnboundedReceiver<DownloaderCommand>,
+    q: &mut AsyncQueue<Job>,
     mut child: Child,
     st: impl Stream<Item = tokio::io::Result<String>>,
-    tx: &broadcast::Sender<DownloaderMsg>,
+    ctx: &mut Ctx,
 ) -> ExitReason {
     info!("In downloader handler");
     tokio::pin!(st); // I expect these buffers to be allocated anyway
     let mut reading_out = true;
     let mut stuck = false;
-    let stuck_timer = tokio::time::sleep(STUCK_DURATION);
+    let stuck_duration = ctx.settings.stuck_timeout();
+    let stuck_timer = tokio::time::sleep(stuck_duration);
     // set if cancel or pause command is received
     let mut user_exitreason: Option<ExitReason> = None;
     tokio::pin!(stuck_timer);
+    ctx.enter("downloading");
+    // what the output showed last, to go back to after being stuck
+    let mut active = DaemonState::Starting;
+    ctx.set_state(active.clone());
+    ctx.internals_tx.send_modify(|i| {
+        i.pid = child.id();
+        i.stuck_at = Some(stuck_timer.deadline());
+    });
+    let mut output_limit = OutputLimit::new(Instant::now());
+    // armed once shutdown is requested
+    let mut shutting_down = false;
+    let shutdown_timer = tokio::time::sleep(Duration::ZERO);
+    tokio::pin!(shutdown_timer);
     loop {
         select! {
             _ = &mut stuck_timer, if ! stuck => {
                 stuck = true;
-                tx.send(DownloaderMsg::Stuck);
+                ctx.update_tx.send(DownloaderMsg::Stuck);
+                if !shutting_down {
+                    ctx.set_state(DaemonState::Stuck);
+                }
             },
             line = st.next(), if reading_out => {
                 stuck = false;
-                stuck_timer.as_mut().reset(Instant::now() + STUCK_DURATION);
+                stuck_timer.as_mut().reset(Instant::now() + stuck_duration);
+                ctx.internals_tx.send_modify(|i| i.stuck_at = Some(stuck_timer.deadline()));
                 if let Some(x) = line {
-                    handle_line(x, tx);
+                    active = handle_line(x, &ctx.update_tx, &mut output_limit).unwrap_or(active);
+                    if !shutting_down {
+                        ctx.set_state(active.clone());
+                    }
                 } else {
                     reading_out = false;
                 }
@@ -312,22 +633,32 @@ async fn handle_downloader(
                     Err(e) => return ExitReason::IOError(e),
                 }
             },
-            cmd = cmd_rx.recv() => {
-                if let Some(cmd) = cmd {
-                    debug!("Command received while downloading: {cmd:?}");
-                    match cmd {
+            cmd = ctx.cmd_rx.recv() => {
+                if let Some(req) = cmd {
+                    debug!("Command received while downloading: {:?}", req.cmd);
+                    match req.cmd {
                         DownloaderCommand::Cancel => {
                             _ = child.kill().await;
                             reading_out = false;
                             user_exitreason = Some(ExitReason::Cancelled);
+                            req.respond(Ok(()));
                         },
                         DownloaderCommand::Pause => {
                             _ = child.kill().await;
                             reading_out = false;
                             user_exitreason = Some(ExitReason::Paused);
+                            req.respond(Ok(()));
+                        },
+                        DownloaderCommand::Resume => {
+                            req.respond(Err(CommandError::InvalidState("not paused")));
+                        },
+                        DownloaderCommand::Retry | DownloaderCommand::Skip => {
+                            req.respond(Err(CommandError::InvalidState("not holding")));
                         },
+                        DownloaderCommand::Ping => req.respond(Ok(())),
                         _ => {
-                            handle_queue_commands(q, &cmd, tx)
+                            let result = handle_queue_commands(q, &req.cmd, &ctx.update_tx);
+                            req.respond(result);
                         },
                     }
                 } else {
@@ -335,7 +666,141 @@ async fn handle_downloader(
                     return ExitReason::Panic;
                 }
             },
+            _ = shutdown::requested(&mut ctx.shutdown_rx), if !shutting_down => {
+                let timeout = ctx.settings.shutdown_timeout();
+                info!("Shutdown requested, waiting up to {timeout:?} for the download");
+                shutting_down = true;
+                ctx.set_state(DaemonState::ShuttingDown);
+                shutdown_timer.as_mut().reset(Instant::now() + timeout);
+                ctx.internals_tx.send_modify(|i| i.stop_at = Some(shutdown_timer.deadline()));
+            },
+            _ = &mut shutdown_timer, if shutting_down => {
+                if user_exitreason.is_none() {
+                    info!("Stopping the downloader");
+                    terminate(&child);
+                    user_exitreason = Some(ExitReason::Shutdown);
+                    shutdown_timer.as_mut().reset(Instant::now() + TERMINATE_GRACE);
+                    ctx.internals_tx.send_modify(|i| i.stop_at = Some(shutdown_timer.deadline()));
+                } else {
+                    warn!("Downloader did not exit, killing it");
+                    _ = child.kill().await;
+                    reading_out = false;
+                }
+            },
+            _ = ctx.watchdog.ping() => {},
         }
     }
     unreachable!()
 }
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+
+    #[test]
+    fn check_output_limit() {
+        let start = Instant::now();
+        let mut limit = OutputLimit::new(start);
+        let allowed = (0..30).filter(|_| limit.allow(start)).count();
+        assert_eq!(allowed, OUTPUT_LINES_PER_SEC as usize);
+        assert!(limit.allow(start + Duration::from_secs(1)));
+        assert_eq!(limit.dropped, 0);
+    }
+
+    fn ctx() -> (Ctx, UnboundedSender<CommandRequest>, broadcast::Receiver<DownloaderMsg>) {
+        let (cmd_tx, cmd_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (update_tx, update_rx) = broadcast::channel(64);
+        let ctx = Ctx {
+            cmd_rx,
+            update_tx,
+            internals_tx: watch::channel(Internals::default()).0,
+            watchdog: Watchdog::from_env(),
+            shutdown_rx: watch::channel(false).1,
+            settings_rx: watch::channel(Settings::default()).1,
+            settings: DownloaderSettings::default(),
+            state: DaemonState::Idle,
+        };
+        (ctx, cmd_tx, update_rx)
+    }
+
+    #[tokio::test]
+    async fn check_transitions() {
+        let (mut ctx, cmd_tx, mut update_rx) = ctx();
+        let mut states = || {
+            let mut states = vec![];
+            while let Ok(msg) = update_rx.try_recv() {
+                if let DownloaderMsg::StateChanged(state) = msg {
+                    states.push(state);
+                }
+            }
+            states
+        };
+        ctx.set_state(DaemonState::Idle);
+        ctx.set_state(DaemonState::Starting);
+        ctx.set_state(DaemonState::Starting);
+        ctx.set_state(DaemonState::Downloading);
+        assert_eq!(states(), [DaemonState::Starting, DaemonState::Downloading]);
+        ctx.hold(HoldReason::Killed);
+        ctx.hold(HoldReason::Killed);
+        ctx.idle();
+        let killed = DaemonState::Held { reason: HoldReason::Killed };
+        assert_eq!(states(), [killed, DaemonState::Idle]);
+
+        // paused and resumed while idle, then a job arrives
+        let mut q = AsyncQueue::new();
+        let mut job = None;
+        for cmd in [DownloaderCommand::Pause, DownloaderCommand::Resume, DownloaderCommand::AddUrl("https://a.example/".into())] {
+            _ = cmd_tx.send(cmd.into());
+        }
+        run_idle(&mut q, &mut job, &mut ctx).await.unwrap();
+        assert_eq!(job.map(|j| j.url), Some("https://a.example/".into()));
+        assert_eq!(states(), [DaemonState::Held { reason: HoldReason::User }, DaemonState::Idle]);
+    }
+
+    #[test]
+    fn check_add() {
+        let (update_tx, _) = broadcast::channel(8);
+        let mut q = AsyncQueue::new();
+        let add = |q: &mut AsyncQueue<Job>, url: &str| handle_queue_commands(q, &DownloaderCommand::AddUrl(url.into()), &update_tx);
+        assert_eq!(add(&mut q, "https://a.example/"), Ok(()));
+        assert_eq!(add(&mut q, "  "), Err(CommandError::Invalid("url is empty".into())));
+        let options = JobOptions { subdir: Some("../up".into()), ..JobOptions::default() };
+        let jobs = vec![Job::new("https://b.example/", JobOptions::default()), Job::new("https://c.example/", options.clone())];
+        let added = handle_queue_commands(&mut q, &DownloaderCommand::AddJobs(jobs), &update_tx);
+        assert!(matches!(added, Err(CommandError::Invalid(e)) if e.starts_with("job 1: ")));
+        assert_eq!(q.len(), 1);
+        let id = q.contents()[0].id;
+        let edit = DownloaderCommand::EditJob { id, url: None, options: Some(options) };
+        assert!(matches!(handle_queue_commands(&mut q, &edit, &update_tx), Err(CommandError::Invalid(_))));
+        let edit = DownloaderCommand::EditJob { id, url: Some(" ".into()), options: None };
+        assert!(matches!(handle_queue_commands(&mut q, &edit, &update_tx), Err(CommandError::Invalid(_))));
+        assert_eq!(q.contents()[0].url, "https://a.example/");
+    }
+
+    #[tokio::test]
+    async fn check_hold() {
+        let (mut ctx, cmd_tx, mut update_rx) = ctx();
+        let failed = (Status::Failed, "Exit code 1");
+        let mut q = AsyncQueue::new();
+        let mut job = Some(Job::new("https://a.example/", JobOptions::default()));
+        // a failed job is tried again on Retry
+        _ = cmd_tx.send(DownloaderCommand::Retry.into());
+        run_hold(&mut q, &mut job, failed, &mut ctx).await.unwrap();
+        assert!(job.is_some());
+        // and given up on Resume
+        _ = cmd_tx.send(DownloaderCommand::Resume.into());
+        run_hold(&mut q, &mut job, failed, &mut ctx).await.unwrap();
+        assert!(job.is_none());
+        let finished = std::iter::from_fn(|| update_rx.try_recv().ok())
+            .find_map(|msg| match msg {
+                DownloaderMsg::Finished { status, reason, .. } => Some((status, reason)),
+                _ => None,
+            });
+        assert_eq!(finished, Some((Status::Failed, "Exit code 1".into())));
+        // a paused one goes on
+        let mut job = Some(Job::new("https://b.example/", JobOptions::default()));
+        _ = cmd_tx.send(DownloaderCommand::Resume.into());
+        run_hold(&mut q, &mut job, SKIPPED, &mut ctx).await.unwrap();
+        assert!(job.is_some());
+    }
+}
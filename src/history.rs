@@ -0,0 +1,690 @@
+//! The record of every download that ended, kept in SQLite. A recorder
+//! follows the downloader's messages and writes one row per job once it is
+//! finished, failed, cancelled or skipped.
+use crate::rate::Counter;
+use crate::job::{JobId, JobOptions};
+use crate::ytdlp::MediaInfo;
+use crate::*;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+mod stats;
+pub use stats::{collector, Period, Stats};
+
+/// Results returned when a query does not ask for a number
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 1000;
+/// Days of totals shown at most
+const TOTAL_DAYS: u32 = 31;
+/// Shortest span the peak rate is measured over
+const PEAK_WINDOW: Duration = Duration::from_secs(1);
+
+/// One entry per schema version, applied in order and tracked in
+/// `PRAGMA user_version`
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE downloads (
+        id INTEGER PRIMARY KEY,
+        job_id INTEGER NOT NULL,
+        url TEXT NOT NULL,
+        title TEXT,
+        path TEXT,
+        bytes INTEGER NOT NULL,
+        started INTEGER NOT NULL,
+        finished INTEGER NOT NULL,
+        avg_rate INTEGER,
+        peak_rate INTEGER,
+        status TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        options TEXT NOT NULL,
+        log TEXT NOT NULL
+    );
+    CREATE INDEX downloads_finished ON downloads (finished);
+", "
+    ALTER TABLE downloads ADD COLUMN deleted INTEGER;
+", "
+    ALTER TABLE downloads ADD COLUMN duration REAL;
+    ALTER TABLE downloads ADD COLUMN description TEXT;
+", "
+    CREATE TABLE traffic (
+        hour INTEGER PRIMARY KEY,
+        bytes INTEGER NOT NULL,
+        seconds REAL NOT NULL
+    );
+    INSERT INTO traffic (hour, bytes, seconds)
+        SELECT finished / 3600, sum(bytes), sum(CASE WHEN avg_rate > 0 THEN bytes * 1.0 / avg_rate ELSE 0 END)
+        FROM downloads GROUP BY finished / 3600;
+", "
+    ALTER TABLE downloads ADD COLUMN domain TEXT NOT NULL DEFAULT '';
+    CREATE INDEX downloads_domain ON downloads (domain);
+"];
+
+/// The version that added `downloads.domain`, filled in from the URLs
+const DOMAIN_VERSION: usize = 5;
+
+const COLUMNS: &str = "id, job_id, url, title, path, bytes, started, finished, avg_rate, peak_rate, \
+    status, reason, attempts, options, log, deleted, duration, description";
+
+/// The conditions of a [`Query`], with its parameters as ?1 to ?5
+const FILTER: &str = "(?1 IS NULL OR url LIKE ?1 ESCAPE '\\' OR title LIKE ?1 ESCAPE '\\' OR path LIKE ?1 ESCAPE '\\')
+    AND (?2 IS NULL OR status = ?2)
+    AND (?3 IS NULL OR finished >= ?3)
+    AND (?4 IS NULL OR finished < ?4)
+    AND (?5 IS NULL OR json_extract(options, '$.subdir') = ?5)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Finished,
+    Failed,
+    Cancelled,
+    Skipped,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Finished => "finished",
+            Status::Failed => "failed",
+            Status::Cancelled => "cancelled",
+            Status::Skipped => "skipped",
+        }
+    }
+}
+
+impl FromStr for Status {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "finished" => Ok(Status::Finished),
+            "failed" => Ok(Status::Failed),
+            "cancelled" => Ok(Status::Cancelled),
+            "skipped" => Ok(Status::Skipped),
+            _ => Err(format!("unknown status: {s}")),
+        }
+    }
+}
+
+/// A download that ended
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Record {
+    /// Row id, unlike job ids it stays the same across restarts
+    pub id: i64,
+    pub job_id: JobId,
+    pub url: Url,
+    pub title: Option<String>,
+    /// Where the file was saved, if it got that far
+    pub path: Option<String>,
+    pub bytes: u64,
+    /// Unix seconds
+    pub started: i64,
+    pub finished: i64,
+    /// Bytes per second while downloading
+    pub avg_rate: Option<u64>,
+    pub peak_rate: Option<u64>,
+    pub status: Status,
+    pub reason: String,
+    /// Downloader processes started, including retries
+    pub attempts: u32,
+    pub options: JobOptions,
+    /// The last lines of downloader output that were not progress
+    pub log: Vec<String>,
+    /// Unix seconds when the file was deleted through downd
+    pub deleted: Option<i64>,
+    /// Length of the media in seconds, as yt-dlp reported it
+    pub duration: Option<f64>,
+    pub description: Option<String>,
+}
+
+/// Downloads that ended on one day, UTC
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DayTotal {
+    /// As YYYY-MM-DD
+    pub day: String,
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// Why a downloaded file was not deleted
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeleteError {
+    NotFound,
+    /// No path was recorded, or the file is gone already
+    NoFile,
+    /// The path is not below the output directory, or there is none
+    Outside,
+    Failed(String),
+}
+
+impl std::fmt::Display for DeleteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeleteError::NotFound => write!(f, "not found"),
+            DeleteError::NoFile => write!(f, "there is no file to delete"),
+            DeleteError::Outside => write!(f, "the file is not in the output directory"),
+            DeleteError::Failed(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Record {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let status: String = row.get(10)?;
+        let options: String = row.get(13)?;
+        let log: String = row.get(14)?;
+        Ok(Self {
+            id: row.get(0)?,
+            job_id: row.get(1)?,
+            url: row.get(2)?,
+            title: row.get(3)?,
+            path: row.get(4)?,
+            bytes: row.get(5)?,
+            started: row.get(6)?,
+            finished: row.get(7)?,
+            avg_rate: row.get(8)?,
+            peak_rate: row.get(9)?,
+            status: Status::from_str(&status).unwrap_or(Status::Failed),
+            reason: row.get(11)?,
+            attempts: row.get(12)?,
+            options: serde_json::from_str(&options).unwrap_or_default(),
+            log: log.lines().map(String::from).collect(),
+            deleted: row.get(15)?,
+            duration: row.get(16)?,
+            description: row.get(17)?,
+        })
+    }
+}
+
+/// Filters for searching the history. Newest downloads come first.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Query {
+    /// Matched against URL, title and path
+    pub text: Option<String>,
+    pub status: Option<Status>,
+    /// Unix seconds or a UTC date like 2024-05-01, compared with the end
+    /// time. A date given as `until` includes that whole day.
+    pub since: Option<String>,
+    pub until: Option<String>,
+    /// The folder the download was saved in, see [`JobOptions::subdir`]
+    pub subdir: Option<String>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+/// Turns unix seconds or a date into unix seconds. `end` moves a bare date
+/// to the start of the next day.
+fn timestamp(conn: &Connection, value: &str, end: bool) -> Result<i64, String> {
+    if let Ok(secs) = value.parse() {
+        return Ok(secs);
+    }
+    let modifier = if end && value.len() == 10 { "+1 day" } else { "+0 days" };
+    conn.query_row("SELECT unixepoch(?1, ?2)", params![value, modifier], |row| row.get::<_, Option<i64>>(0))
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("not a date: {value}"))
+}
+
+/// `text` as a LIKE pattern that matches it anywhere
+fn like_pattern(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{escaped}%")
+}
+
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let version: u64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration)?;
+        if i + 1 == DOMAIN_VERSION {
+            stats::fill_domains(&tx)?;
+        }
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn insert(conn: &Connection, record: &Record) -> rusqlite::Result<i64> {
+    conn.execute(
+        "INSERT INTO downloads (job_id, url, title, path, bytes, started, finished, avg_rate, peak_rate, \
+            status, reason, attempts, options, log, duration, description, domain)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+        params![
+            record.job_id,
+            record.url,
+            record.title,
+            record.path,
+            record.bytes,
+            record.started,
+            record.finished,
+            record.avg_rate,
+            record.peak_rate,
+            record.status.as_str(),
+            record.reason,
+            record.attempts,
+            serde_json::to_string(&record.options).unwrap_or_default(),
+            record.log.join("\n"),
+            record.duration,
+            record.description,
+            stats::domain(&record.url),
+        ],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// The parameters for [`FILTER`]
+type FilterParams = (Option<String>, Option<&'static str>, Option<i64>, Option<i64>, Option<String>);
+
+fn filter_params(conn: &Connection, query: &Query) -> Result<FilterParams, String> {
+    let text = query.text.as_deref().filter(|t| !t.is_empty()).map(like_pattern);
+    let since = query.since.as_deref().map(|s| timestamp(conn, s, false)).transpose()?;
+    let until = query.until.as_deref().map(|s| timestamp(conn, s, true)).transpose()?;
+    let subdir = query.subdir.clone().filter(|s| !s.is_empty());
+    Ok((text, query.status.map(Status::as_str), since, until, subdir))
+}
+
+fn search(conn: &Connection, query: &Query) -> Result<Vec<Record>, String> {
+    let (text, status, since, until, subdir) = filter_params(conn, query)?;
+    let sql = format!(
+        "SELECT {COLUMNS} FROM downloads WHERE {FILTER}
+         ORDER BY finished DESC, id DESC
+         LIMIT ?6 OFFSET ?7"
+    );
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let mut statement = conn.prepare_cached(&sql).map_err(|e| e.to_string())?;
+    let rows = statement
+        .query_map(
+            params![text, status, since, until, subdir, limit, query.offset.unwrap_or(0)],
+            Record::from_row,
+        )
+        .map_err(|e| e.to_string())?;
+    rows.collect::<rusqlite::Result<_>>().map_err(|e| e.to_string())
+}
+
+/// Count and size of the matching downloads per day, newest first. Limit
+/// and offset are ignored.
+fn totals(conn: &Connection, query: &Query) -> Result<Vec<DayTotal>, String> {
+    let (text, status, since, until, subdir) = filter_params(conn, query)?;
+    let sql = format!(
+        "SELECT date(finished, 'unixepoch') AS day, count(*), sum(bytes) FROM downloads WHERE {FILTER}
+         GROUP BY day ORDER BY day DESC LIMIT {TOTAL_DAYS}"
+    );
+    let mut statement = conn.prepare_cached(&sql).map_err(|e| e.to_string())?;
+    let rows = statement
+        .query_map(params![text, status, since, until, subdir], |row| {
+            Ok(DayTotal { day: row.get(0)?, count: row.get(1)?, bytes: row.get(2)? })
+        })
+        .map_err(|e| e.to_string())?;
+    rows.collect::<rusqlite::Result<_>>().map_err(|e| e.to_string())
+}
+
+fn get(conn: &Connection, id: i64) -> rusqlite::Result<Option<Record>> {
+    conn.query_row(&format!("SELECT {COLUMNS} FROM downloads WHERE id = ?1"), [id], Record::from_row)
+        .optional()
+}
+
+/// Deletes the file of a download if it is below `root`, and notes that it
+/// was deleted. Without a root nothing is deleted.
+fn delete_file(conn: &Connection, id: i64, root: Option<&Path>) -> Result<(), DeleteError> {
+    let failed = |e: &dyn std::fmt::Display| DeleteError::Failed(e.to_string());
+    let record = get(conn, id).map_err(|e| failed(&e))?.ok_or(DeleteError::NotFound)?;
+    let path = record.path.filter(|_| record.deleted.is_none()).ok_or(DeleteError::NoFile)?;
+    // resolves links and `..`, so a recorded path cannot point elsewhere
+    let path = match Path::new(&path).canonicalize() {
+        Ok(path) => path,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(DeleteError::NoFile),
+        Err(e) => return Err(failed(&e)),
+    };
+    let root = root.ok_or(DeleteError::Outside)?.canonicalize().map_err(|e| failed(&e))?;
+    if !path.starts_with(root) {
+        return Err(DeleteError::Outside);
+    }
+    if !path.is_file() {
+        return Err(DeleteError::NoFile);
+    }
+    std::fs::remove_file(&path).map_err(|e| failed(&e))?;
+    info!("Deleted {}", path.display());
+    let now = unix_secs(SystemTime::now());
+    conn.execute("UPDATE downloads SET deleted = ?1 WHERE id = ?2", params![now, id])
+        .map_err(|e| failed(&e))?;
+    Ok(())
+}
+
+/// Shared handle to the database. Queries run on the blocking thread pool.
+#[derive(Clone)]
+pub struct History {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl History {
+    /// Opens or creates the database, bringing the schema up to date
+    pub fn open(path: &Path) -> Result<Self, String> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+        }
+        let conn = Connection::open(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Self::with_connection(conn)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self, String> {
+        migrate(&conn).map_err(|e| format!("Could not set up the history database: {e}"))?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Connection) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, String> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&conn.lock().unwrap()))
+            .await
+            .map_err(|e| e.to_string())?
+    }
+
+    /// Stores a record, returning its id
+    pub async fn insert(&self, record: Record) -> Result<i64, String> {
+        self.run(move |conn| insert(conn, &record).map_err(|e| e.to_string())).await
+    }
+
+    pub async fn search(&self, query: Query) -> Result<Vec<Record>, String> {
+        self.run(move |conn| search(conn, &query)).await
+    }
+
+    pub async fn totals(&self, query: Query) -> Result<Vec<DayTotal>, String> {
+        self.run(move |conn| totals(conn, &query)).await
+    }
+
+    /// Totals for today, this week and all time, UTC
+    pub async fn stats(&self) -> Result<Stats, String> {
+        let now = unix_secs(SystemTime::now());
+        self.run(move |conn| stats::read(conn, now).map_err(|e| e.to_string())).await
+    }
+
+    pub async fn get(&self, id: i64) -> Result<Option<Record>, String> {
+        self.run(move |conn| get(conn, id).map_err(|e| e.to_string())).await
+    }
+
+    /// Deletes the downloaded file, refusing files outside of `root`
+    pub async fn delete_file(&self, id: i64, root: Option<PathBuf>) -> Result<(), DeleteError> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || delete_file(&conn.lock().unwrap(), id, root.as_deref()))
+            .await
+            .map_err(|e| DeleteError::Failed(e.to_string()))?
+    }
+}
+
+fn unix_secs(t: SystemTime) -> i64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+/// What is known so far about the job being downloaded
+#[derive(Debug, Default)]
+struct Recording {
+    started: Option<SystemTime>,
+    title: Option<String>,
+    path: Option<String>,
+    info: MediaInfo,
+    counter: Counter,
+    /// When the downloader process of the last attempt started
+    spawned: Option<SystemTime>,
+    last_progress: Option<SystemTime>,
+    /// Start of the current peak rate window, with the bytes at that time
+    window: Option<(SystemTime, u64)>,
+    peak_rate: Option<u64>,
+    attempts: u32,
+    log: VecDeque<String>,
+}
+
+impl Recording {
+    fn bytes(&self) -> u64 {
+        self.counter.bytes()
+    }
+
+    /// Follows one message, returning the record once the job ended
+    fn update(&mut self, msg: DownloaderMsg, now: SystemTime, log_lines: usize) -> Option<Record> {
+        use DownloaderMsg::*;
+        match msg {
+            Current(_) => {
+                *self = Recording {
+                    started: Some(now),
+                    ..Recording::default()
+                };
+            }
+            Starting(title) | Moved(title) => self.title = title.or(self.title.take()),
+            Saved(path) => self.path = Some(path),
+            Info(info) => self.info = info,
+            // a new attempt downloads from the start again
+            Spawned { attempt } => {
+                self.attempts = attempt;
+                self.counter = Counter::default();
+                self.spawned = Some(now);
+                self.window = None;
+            }
+            Downloading { downloaded_bytes, .. } => {
+                self.counter.total(downloaded_bytes);
+                self.last_progress = Some(now);
+                let bytes = self.bytes();
+                match self.window {
+                    Some((start, start_bytes)) => {
+                        let elapsed = now.duration_since(start).unwrap_or_default();
+                        if elapsed >= PEAK_WINDOW {
+                            let rate = (bytes.saturating_sub(start_bytes) as f64 / elapsed.as_secs_f64()) as u64;
+                            self.peak_rate = self.peak_rate.max(Some(rate));
+                            self.window = Some((now, bytes));
+                        }
+                    }
+                    None => self.window = Some((now, bytes)),
+                }
+            }
+            Output(line) => {
+                if log_lines > 0 {
+                    if self.log.len() == log_lines {
+                        self.log.pop_front();
+                    }
+                    self.log.push_back(line);
+                }
+            }
+            Finished { job, status, reason } => {
+                let recording = std::mem::take(self);
+                let bytes = recording.bytes();
+                let avg_rate = match (recording.spawned, recording.last_progress) {
+                    (Some(first), Some(last)) => last
+                        .duration_since(first)
+                        .ok()
+                        .filter(|d| *d >= PEAK_WINDOW)
+                        .map(|d| (bytes as f64 / d.as_secs_f64()) as u64),
+                    _ => None,
+                };
+                return Some(Record {
+                    id: 0,
+                    job_id: job.id,
+                    url: job.url,
+                    title: recording.title,
+                    path: recording.path,
+                    bytes,
+                    started: unix_secs(recording.started.unwrap_or(now)),
+                    finished: unix_secs(now),
+                    avg_rate,
+                    peak_rate: recording.peak_rate,
+                    status,
+                    reason,
+                    attempts: recording.attempts,
+                    options: job.options,
+                    log: recording.log.into(),
+                    deleted: None,
+                    duration: recording.info.duration,
+                    description: recording.info.description,
+                });
+            }
+            Stuck | Idle | Hold(_) | QueueUpdate(_) | ConfigReloaded(_) | PostProcessing
+            | StateChanged(_) => {}
+        }
+        None
+    }
+}
+
+/// Writes a record for every job that ends
+pub async fn recorder(mut update_rx: broadcast::Receiver<DownloaderMsg>, history: History, log_lines: usize) {
+    use broadcast::error::RecvError;
+    let mut recording = Recording::default();
+    loop {
+        let msg = match update_rx.recv().await {
+            Ok(msg) => msg,
+            Err(RecvError::Lagged(n)) => {
+                warn!("history recorder skipped {n} updates");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if let Some(record) = recording.update(msg, SystemTime::now(), log_lines) {
+            if let Err(e) = history.insert(record).await {
+                error!("Could not record download: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod checks {
+    use super::*;
+    use crate::job::Job;
+
+    /// A download with nothing but the given fields set
+    pub(crate) fn record(url: &str, finished: i64, status: Status) -> Record {
+        Record {
+            id: 0,
+            job_id: 1,
+            url: url.into(),
+            title: None,
+            path: None,
+            bytes: 0,
+            started: finished,
+            finished,
+            avg_rate: None,
+            peak_rate: None,
+            status,
+            reason: String::new(),
+            attempts: 1,
+            options: JobOptions::default(),
+            log: vec![],
+            deleted: None,
+            duration: None,
+            description: None,
+        }
+    }
+
+    fn progress(downloaded_bytes: u64) -> DownloaderMsg {
+        DownloaderMsg::Downloading {
+            downloaded_bytes,
+            total_bytes: None,
+            frag_index: None,
+            frag_count: None,
+            speed: None,
+        }
+    }
+
+    #[test]
+    fn check_recording() {
+        let job = Job::new("https://example.com/v", JobOptions { audio_only: true, ..JobOptions::default() });
+        let t0 = UNIX_EPOCH + Duration::from_secs(1000);
+        let at = |secs: u64| t0 + Duration::from_secs(secs);
+        let mut r = Recording::default();
+        let msgs = [
+            (0, DownloaderMsg::Current(job.clone())),
+            (0, DownloaderMsg::Spawned { attempt: 1 }),
+            (1, progress(500)),
+            (1, DownloaderMsg::Output("ERROR: timed out".into())),
+            (2, DownloaderMsg::Spawned { attempt: 2 }),
+            (2, DownloaderMsg::Starting(Some("A video".into()))),
+            (3, progress(1000)),
+            (4, progress(4000)),
+            // the audio stream starts from zero
+            (5, progress(1000)),
+            (5, DownloaderMsg::Saved("/tmp/a.mp4".into())),
+            (5, DownloaderMsg::Info(MediaInfo { duration: Some(61.5), description: None })),
+            (6, DownloaderMsg::Output("WARNING: one".into())),
+            (6, DownloaderMsg::Output("WARNING: two".into())),
+        ];
+        for (secs, msg) in msgs {
+            assert_eq!(r.update(msg, at(secs), 2), None);
+        }
+        let finished = DownloaderMsg::Finished { job: job.clone(), status: Status::Finished, reason: "Finished".into() };
+        let record = r.update(finished, at(7), 2).unwrap();
+        assert_eq!(record.bytes, 5000);
+        assert_eq!(record.started, 1000);
+        assert_eq!(record.finished, 1007);
+        assert_eq!(record.avg_rate, Some(1666));
+        assert_eq!(record.peak_rate, Some(3000));
+        assert_eq!(record.attempts, 2);
+        assert_eq!(record.title.as_deref(), Some("A video"));
+        assert_eq!(record.path.as_deref(), Some("/tmp/a.mp4"));
+        assert_eq!(record.duration, Some(61.5));
+        assert_eq!(record.status, Status::Finished);
+        assert_eq!(record.log, ["WARNING: one", "WARNING: two"]);
+        assert!(record.options.audio_only);
+        // nothing carries over to the next job
+        let skipped = DownloaderMsg::Finished { job, status: Status::Failed, reason: "Exit code 1".into() };
+        let record = r.update(skipped, at(8), 2).unwrap();
+        assert_eq!((record.bytes, record.attempts, record.status), (0, 0, Status::Failed));
+    }
+
+    #[tokio::test]
+    async fn check_search() {
+        let history = History::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        // 2024-05-01 and 2024-05-02, both at noon UTC
+        history.insert(record("https://a.example/100%", 1714564800, Status::Finished)).await.unwrap();
+        let id = history.insert(record("https://b.example/x", 1714651200, Status::Failed)).await.unwrap();
+        let urls = |records: Vec<Record>| records.into_iter().map(|r| r.url).collect::<Vec<_>>();
+        let all = history.search(Query::default()).await.unwrap();
+        assert_eq!(urls(all), ["https://b.example/x", "https://a.example/100%"]);
+        let query = Query { text: Some("0%".into()), ..Query::default() };
+        assert_eq!(urls(history.search(query).await.unwrap()), ["https://a.example/100%"]);
+        let query = Query { status: Some(Status::Failed), ..Query::default() };
+        assert_eq!(urls(history.search(query).await.unwrap()), ["https://b.example/x"]);
+        let query = Query { until: Some("2024-05-01".into()), ..Query::default() };
+        assert_eq!(urls(history.search(query).await.unwrap()), ["https://a.example/100%"]);
+        let query = Query { since: Some("1714600000".into()), ..Query::default() };
+        assert_eq!(urls(history.search(query).await.unwrap()), ["https://b.example/x"]);
+        let query = Query { since: Some("yesterday".into()), ..Query::default() };
+        assert!(history.search(query).await.is_err());
+        assert_eq!(history.get(id).await.unwrap().unwrap().status, Status::Failed);
+        assert_eq!(history.get(id + 1).await.unwrap(), None);
+        let days = history.totals(Query { limit: Some(1), ..Query::default() }).await.unwrap();
+        assert_eq!(days.iter().map(|d| (d.day.as_str(), d.count)).collect::<Vec<_>>(), [("2024-05-02", 1), ("2024-05-01", 1)]);
+        let mut talk = record("https://c.example/talk", 1714651200, Status::Finished);
+        talk.options.subdir = Some("talks".into());
+        history.insert(talk).await.unwrap();
+        let query = Query { subdir: Some("talks".into()), ..Query::default() };
+        assert_eq!(urls(history.search(query).await.unwrap()), ["https://c.example/talk"]);
+    }
+
+    #[test]
+    fn check_delete_file() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        let root = std::env::temp_dir().join(format!("downd-check-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("out")).unwrap();
+        let add = |path: PathBuf| {
+            std::fs::write(&path, "x").unwrap();
+            let record = Record { path: Some(path.to_string_lossy().into()), ..record("x", 0, Status::Finished) };
+            (insert(&conn, &record).unwrap(), path)
+        };
+        let (inside, inside_path) = add(root.join("out/a.mp4"));
+        let (outside, outside_path) = add(root.join("b.mp4"));
+        let out = root.join("out");
+        assert_eq!(delete_file(&conn, outside, Some(&out)), Err(DeleteError::Outside));
+        assert!(outside_path.exists());
+        // no output directory configured
+        assert_eq!(delete_file(&conn, outside, None), Err(DeleteError::Outside));
+        assert_eq!(delete_file(&conn, inside, None), Err(DeleteError::Outside));
+        assert!(outside_path.exists() && inside_path.exists());
+        assert_eq!(delete_file(&conn, inside, Some(&out)), Ok(()));
+        assert!(!inside_path.exists());
+        assert!(get(&conn, inside).unwrap().unwrap().deleted.is_some());
+        assert_eq!(delete_file(&conn, inside, Some(&out)), Err(DeleteError::NoFile));
+        assert_eq!(delete_file(&conn, 99, None), Err(DeleteError::NotFound));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+//! Statistics that outlive the daemon. Bytes and time spent downloading are
+//! tallied per hour as progress comes in, so retries, failures and the
+//! download in progress count as well. Jobs and domains are counted from the
+//! downloads table.
+use super::{unix_secs, History};
+use crate::rate::Counter;
+use crate::*;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+/// How often the tally is written while downloading
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+const TOP_DOMAINS: usize = 10;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Period {
+    pub bytes: u64,
+    /// Time spent downloading
+    pub seconds: f64,
+    pub finished: u64,
+    pub failed: u64,
+    /// Bytes per second while downloading
+    pub avg_rate: Option<u64>,
+}
+
+/// Bytes downloaded in one hour of the day, UTC, summed over all days
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HourTotal {
+    pub hour: u8,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DomainTotal {
+    pub domain: String,
+    pub jobs: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    /// Since midnight UTC
+    pub today: Period,
+    /// Since Monday, midnight UTC
+    pub week: Period,
+    pub all_time: Period,
+    /// All 24 hours, from 0
+    pub hours: Vec<HourTotal>,
+    /// The domains with the most jobs, first the busiest
+    pub top_domains: Vec<DomainTotal>,
+}
+
+/// Starts of today and this week, in unix seconds
+fn period_starts(now: i64) -> (i64, i64) {
+    let day = now.div_euclid(86400);
+    // the epoch was a Thursday
+    let monday = day - (day + 3).rem_euclid(7);
+    (day * 86400, monday * 86400)
+}
+
+/// The host of a URL, without `www.`
+pub(super) fn domain(url: &str) -> String {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    let host = host.to_lowercase();
+    host.strip_prefix("www.").map(String::from).unwrap_or(host)
+}
+
+/// Sets the domain of the downloads recorded before it was stored
+pub(super) fn fill_domains(conn: &Connection) -> rusqlite::Result<()> {
+    let mut select = conn.prepare("SELECT id, url FROM downloads")?;
+    let mut update = conn.prepare("UPDATE downloads SET domain = ?1 WHERE id = ?2")?;
+    for row in select.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))? {
+        let (id, url) = row?;
+        update.execute(params![domain(&url), id])?;
+    }
+    Ok(())
+}
+
+fn period(conn: &Connection, since: i64) -> rusqlite::Result<Period> {
+    let (bytes, seconds): (u64, f64) = conn.query_row(
+        "SELECT coalesce(sum(bytes), 0), coalesce(sum(seconds), 0.0) FROM traffic WHERE hour >= ?1",
+        [since.div_euclid(3600)],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let (finished, failed) = conn.query_row(
+        "SELECT coalesce(sum(status = 'finished'), 0), coalesce(sum(status = 'failed'), 0)
+         FROM downloads WHERE finished >= ?1",
+        [since],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let avg_rate = (seconds > 0.0).then(|| (bytes as f64 / seconds) as u64);
+    Ok(Period { bytes, seconds, finished, failed, avg_rate })
+}
+
+pub(super) fn read(conn: &Connection, now: i64) -> rusqlite::Result<Stats> {
+    let (today, week) = period_starts(now);
+    let mut hours: Vec<_> = (0..24).map(|hour| HourTotal { hour, bytes: 0 }).collect();
+    let mut statement = conn.prepare_cached("SELECT hour % 24, sum(bytes) FROM traffic GROUP BY 1")?;
+    for row in statement.query_map([], |row| Ok((row.get::<_, usize>(0)?, row.get::<_, u64>(1)?)))? {
+        let (hour, bytes) = row?;
+        if let Some(total) = hours.get_mut(hour) {
+            total.bytes = bytes;
+        }
+    }
+    let mut statement = conn.prepare_cached(
+        "SELECT domain, count(*), sum(bytes) FROM downloads GROUP BY domain
+         ORDER BY 2 DESC, 3 DESC, 1 LIMIT ?1",
+    )?;
+    let top_domains = statement
+        .query_map([TOP_DOMAINS], |row| Ok(DomainTotal { domain: row.get(0)?, jobs: row.get(1)?, bytes: row.get(2)? }))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(Stats {
+        today: period(conn, today)?,
+        week: period(conn, week)?,
+        all_time: period(conn, i64::MIN)?,
+        hours,
+        top_domains,
+    })
+}
+
+/// Bytes and seconds per hour since the epoch
+type Hours = BTreeMap<i64, (u64, f64)>;
+
+fn add(conn: &Connection, hours: &Hours) -> rusqlite::Result<()> {
+    let mut statement = conn.prepare_cached(
+        "INSERT INTO traffic (hour, bytes, seconds) VALUES (?1, ?2, ?3)
+         ON CONFLICT (hour) DO UPDATE SET bytes = bytes + excluded.bytes, seconds = seconds + excluded.seconds",
+    )?;
+    for (hour, (bytes, seconds)) in hours {
+        statement.execute(params![hour, bytes, seconds])?;
+    }
+    Ok(())
+}
+
+/// What was downloaded since the last write
+#[derive(Debug, Default)]
+struct Tally {
+    hours: Hours,
+    counter: Counter,
+    /// Unset while nothing is downloading
+    last_progress: Option<SystemTime>,
+}
+
+impl Tally {
+    /// Follows one message, returning whether it is a good time to write
+    fn update(&mut self, msg: &DownloaderMsg, now: SystemTime) -> bool {
+        use DownloaderMsg::*;
+        match msg {
+            Spawned { .. } => {
+                self.counter = Counter::default();
+                self.last_progress = None;
+            }
+            Downloading { downloaded_bytes, .. } => {
+                let bytes = self.counter.delta(*downloaded_bytes);
+                let seconds = match self.last_progress {
+                    Some(last) => now.duration_since(last).unwrap_or_default().as_secs_f64(),
+                    None => 0.0,
+                };
+                self.last_progress = Some(now);
+                let total = self.hours.entry(unix_secs(now).div_euclid(3600)).or_default();
+                total.0 += bytes;
+                total.1 += seconds;
+            }
+            // the time until the next progress is not spent downloading
+            Stuck | Hold(_) | Idle => self.last_progress = None,
+            Finished { .. } => {
+                self.last_progress = None;
+                return true;
+            }
+            StateChanged(_) => return true,
+            Starting(_) | Moved(_) | PostProcessing | QueueUpdate(_) | Current(_) | ConfigReloaded(_) | Saved(_)
+            | Output(_) | Info(_) => {}
+        }
+        false
+    }
+
+    async fn write(&mut self, history: &History) {
+        if self.hours.is_empty() {
+            return;
+        }
+        let hours = std::mem::take(&mut self.hours);
+        if let Err(e) = history.run(move |conn| add(conn, &hours).map_err(|e| e.to_string())).await {
+            error!("Could not save the download statistics: {e}");
+        }
+    }
+}
+
+/// Tallies the bytes downloaded, writing them when the state changes and
+/// every minute while downloading
+pub async fn collector(mut update_rx: broadcast::Receiver<DownloaderMsg>, history: History) {
+    use broadcast::error::RecvError;
+    let mut tally = Tally::default();
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    flush.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            msg = update_rx.recv() => match msg {
+                Ok(msg) => {
+                    if tally.update(&msg, SystemTime::now()) {
+                        tally.write(&history).await;
+                    }
+                }
+                Err(RecvError::Lagged(n)) => warn!("statistics collector skipped {n} updates"),
+                Err(RecvError::Closed) => break,
+            },
+            _ = flush.tick() => tally.write(&history).await,
+        }
+    }
+    tally.write(&history).await;
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+    use crate::history::{checks, insert, migrate, Record, Status};
+    use crate::job::{Job, JobOptions};
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn check_stats() {
+        // Wednesday 2024-05-01, 12:00 UTC
+        let now = 1714564800;
+        assert_eq!(period_starts(now), (1714521600, 1714348800));
+        assert_eq!(period_starts(4 * 86400 + 5), (4 * 86400, 4 * 86400));
+        assert_eq!(domain("https://user@WWW.Example.com:8080/v?x"), "example.com");
+        assert_eq!(domain("www.google.com"), "google.com");
+        assert_eq!(domain("http://[::1]:3000/a"), "[::1]");
+
+        let at = |secs: i64| UNIX_EPOCH + Duration::from_secs(secs as u64);
+        let progress = |downloaded_bytes| DownloaderMsg::Downloading {
+            downloaded_bytes,
+            total_bytes: None,
+            frag_index: None,
+            frag_count: None,
+            speed: None,
+        };
+        let job = Job::new("https://a.example/1", JobOptions::default());
+        let mut tally = Tally::default();
+        let msgs = [
+            (now - 86400, DownloaderMsg::Spawned { attempt: 1 }),
+            (now - 86400, progress(1000)),
+            (now - 86390, progress(3000)),
+            (now - 86390, DownloaderMsg::Stuck),
+            (now - 86000, progress(4000)),
+            (now - 86000, DownloaderMsg::Spawned { attempt: 2 }),
+            (now - 10, progress(500)),
+            (now - 5, progress(1500)),
+            // the audio stream starts from zero
+            (now, progress(1000)),
+        ];
+        for (secs, msg) in msgs {
+            assert!(!tally.update(&msg, at(secs)));
+        }
+        assert!(tally.update(&DownloaderMsg::Finished { job, status: Status::Finished, reason: "Finished".into() }, at(now)));
+
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        add(&conn, &tally.hours).unwrap();
+        add(&conn, &tally.hours).unwrap();
+        let record = |url, bytes, status| Record { bytes, ..checks::record(url, now, status) };
+        insert(&conn, &record("https://b.example/1", 5, Status::Finished)).unwrap();
+        insert(&conn, &record("https://www.a.example/1", 2500, Status::Finished)).unwrap();
+        insert(&conn, &record("https://a.example/2", 0, Status::Failed)).unwrap();
+        let stats = read(&conn, now).unwrap();
+        assert_eq!(stats.today, Period { bytes: 5000, seconds: 20.0, finished: 2, failed: 1, avg_rate: Some(250) });
+        assert_eq!((stats.all_time.bytes, stats.all_time.seconds), (13000, 40.0));
+        assert_eq!(stats.week, stats.all_time);
+        assert_eq!(stats.hours[11].bytes, 3000);
+        assert_eq!(stats.hours[12].bytes, 10000);
+        let domains: Vec<_> = stats.top_domains.iter().map(|d| (d.domain.as_str(), d.jobs, d.bytes)).collect();
+        assert_eq!(domains, [("a.example", 2, 2500), ("b.example", 1, 5)]);
+
+        // as recorded before the domain was stored
+        conn.execute("UPDATE downloads SET domain = ''", []).unwrap();
+        fill_domains(&conn).unwrap();
+        assert_eq!(read(&conn, now).unwrap().top_domains, stats.top_domains);
+    }
+}
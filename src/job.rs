@@ -0,0 +1,117 @@
+//! Queue entries: a URL and the options it is downloaded with
+use crate::Url;
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub type JobId = u64;
+
+/// Ids are handed out once per run, they are not kept in the queue file
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: JobId,
+    pub url: Url,
+    pub options: JobOptions,
+}
+
+/// Per-job settings, applied on top of the downloader settings
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobOptions {
+    /// yt-dlp format selector, passed as `-f`
+    pub format: Option<String>,
+    /// Extract the audio track only
+    pub audio_only: bool,
+    /// Overrides `downloader.rate_limit`
+    pub rate_limit: Option<String>,
+    /// Directory below `downloader.output_dir` to save to
+    pub subdir: Option<String>,
+}
+
+/// A job as submitted by a client, before it has an id
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobSpec {
+    pub url: Url,
+    #[serde(default)]
+    pub options: JobOptions,
+}
+
+impl Job {
+    pub fn new(url: impl Into<Url>, options: JobOptions) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            url: url.into(),
+            options,
+        }
+    }
+    pub fn spec(&self) -> JobSpec {
+        JobSpec {
+            url: self.url.clone(),
+            options: self.options.clone(),
+        }
+    }
+}
+
+impl From<JobSpec> for Job {
+    fn from(spec: JobSpec) -> Self {
+        Job::new(spec.url, spec.options)
+    }
+}
+
+impl JobSpec {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_url(&self.url)?;
+        self.options.validate()
+    }
+}
+
+pub fn validate_url(url: &str) -> Result<(), String> {
+    if url.trim().is_empty() {
+        return Err("url is empty".into());
+    }
+    if url.contains(['\n', '\r']) {
+        return Err("url contains a line break".into());
+    }
+    Ok(())
+}
+
+impl JobOptions {
+    /// Rejects options that would escape the output directory
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(subdir) = &self.subdir {
+            let path = Path::new(subdir);
+            if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(format!("subdir must be a relative path without '..': {subdir}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+
+    #[test]
+    fn check_subdir() {
+        let opts = |subdir: &str| JobOptions {
+            subdir: Some(subdir.into()),
+            ..Default::default()
+        };
+        assert!(opts("music/live").validate().is_ok());
+        assert!(opts("../music").validate().is_err());
+        assert!(opts("/tmp").validate().is_err());
+        assert!(opts("a/../../b").validate().is_err());
+    }
+
+    #[test]
+    fn check_spec() {
+        let spec: JobSpec = serde_json::from_str(r#"{"url": "x", "options": {"audio_only": true}}"#).unwrap();
+        assert!(spec.options.audio_only);
+        assert!(serde_json::from_str::<JobSpec>(r#"{"url": "x", "bogus": 1}"#).is_err());
+        assert!(JobSpec { url: " ".into(), options: Default::default() }.validate().is_err());
+    }
+}
@@ -5,6 +5,7 @@ use std::{
     task::{Context, Poll},
     sync::{Arc, Mutex},
     path::{Path, PathBuf},
+    net::SocketAddr,
 };
 use tokio::{
     sync::broadcast,
@@ -23,16 +24,26 @@ use downloader::*;
 
 mod unixsocket;
 mod commands;
+mod job;
 pub use commands::DownloaderCommand;
 mod webapp;
 use webapp::server;
 
 mod testcode;
-mod rollingrate;
+mod rate;
 
 mod tracker;
 use tracker::*;
 
+mod tui;
+mod systemd;
+mod shutdown;
+mod config;
+mod history;
+mod state;
+pub use state::{DaemonState, HoldReason};
+pub use config::{Config, Command, Settings};
+
 pub use tracing::{debug, error, info, trace, warn};
 
 type Anything<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
@@ -47,79 +58,177 @@ enum LogLevel {
     Trace
 }
 
-#[derive(clap::Parser, Debug)]
-struct Config {
-    #[clap(short = 'p', long = "port", default_value = "3000")]
-    port: u16,
-    #[clap(short = 's', long = "socket")]
-    socket: Option<std::path::PathBuf>,
-    #[clap(short = 'v', action = clap::ArgAction::Count)]
-    verbosity: u8,
-}
-
-async fn setup(config: &Config) {
-    let loglevel = match config.verbosity {
-        0 => tracing::Level::ERROR,
-        1 => tracing::Level::INFO,
-        2 => tracing::Level::DEBUG,
-        _ => tracing::Level::TRACE,
-    };
+async fn setup(settings: &Settings) {
     let subscriber = tracing_subscriber::FmtSubscriber::builder()
-        .with_max_level(loglevel)
+        .with_max_level(settings.log.level())
         .finish();
     tracing::subscriber::set_global_default(subscriber).expect("Failed to start tracing");
 }
 
-fn get_socket_path(config: &Config) -> Result<PathBuf, String> {
-    if let Some(p) = config.socket.clone() {
-        return Ok(p)
-    } 
-    if let Ok(d) = std::env::var("XDG_RUNTIME_DIR") {
-        let mut socket_path = PathBuf::new();
-        socket_path.push(d);
-        socket_path.push("downd");
-        debug!("Using default socket path");
-        Ok(socket_path)
-    } else {
-        error!("Socket path must be specified");
-        Err("No socket path".into())
-    }
+fn resolve_groups(groups: &[String]) -> Result<Vec<u32>, String> {
+    groups
+        .iter()
+        .map(|g| unixsocket::resolve_group(g).ok_or(format!("Unknown group: {g}")))
+        .collect()
+}
+
+/// The GID of a group, if one is configured
+fn resolve_gid(group: Option<&str>) -> Result<Option<u32>, String> {
+    group.map(|g| unixsocket::resolve_group(g).ok_or(format!("Unknown group: {g}"))).transpose()
+}
+
+fn access_policy(settings: &Settings) -> Result<unixsocket::AccessPolicy, String> {
+    let socket = &settings.socket;
+    Ok(unixsocket::AccessPolicy {
+        own_uid: unixsocket::current_uid(),
+        allow_uids: socket.allow_uids.clone(),
+        allow_gids: resolve_groups(&socket.allow_groups)?,
+        readonly_uids: socket.readonly_uids.clone(),
+        readonly_gids: resolve_groups(&socket.readonly_groups)?,
+    })
 }
 
 use tokio::net::UnixListener;
-async fn start_unix_socket(socket_path: impl AsRef<Path>) -> std::io::Result<UnixListener> {
-    // attempt to remove the socket, if it exists already
-    if let Ok(true) = tokio::fs::try_exists(&socket_path).await {
-        tokio::fs::remove_file(&socket_path).await?;
-    }
-    UnixListener::bind(socket_path)
+
+fn main() -> Anything<()> {
+    // before the runtime spawns its worker threads
+    let listen_env = systemd::ListenEnv::take();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(listen_env))
 }
 
-// #[tokio::main(flavor = "current_thread")]
-#[tokio::main]
-async fn main() -> Anything<()> {
+async fn run(listen_env: systemd::ListenEnv) -> Anything<()> {
     let c: Config = clap::Parser::parse();
-    setup(&c).await;
-    // figure out the path for the unix socket
-    let socket_path = get_socket_path(&c)?;
-    info!("Socket path is: {:?}", socket_path);
-    let socket = start_unix_socket(socket_path).await?;
+    let settings = Settings::load(&c)?;
+    if c.print_config {
+        print!("{}", toml::to_string_pretty(&settings)?);
+        return Ok(());
+    }
+    match c.command {
+        // log output would garble the terminal
+        Some(Command::Tui) => return tui::run(settings.socket_path()?.to_path_buf()).await,
+        Some(Command::HashPassword) => return hash_password(),
+        None => {}
+    }
+    setup(&settings).await;
+    let activated = systemd::listen_fds(listen_env);
+    // groups are resolved first, so a typo does not leave socket files behind
+    let policy = access_policy(&settings)?;
+    let socket_gid = resolve_gid(settings.socket.group.as_deref())?;
+    let web_gid = resolve_gid(settings.web.socket_group.as_deref())?;
+    // only set if we created the socket file, and thus have to remove it
+    let mut socket_path = None;
+    let socket = if let Some(listener) = activated.control {
+        listener.set_nonblocking(true)?;
+        UnixListener::from_std(listener)?
+    } else {
+        // figure out the path for the unix socket
+        let path = settings.socket_path()?.to_path_buf();
+        info!("Socket path is: {:?}", path);
+        let socket = unixsocket::bind(&path, socket_gid, settings.socket_mode()).await?;
+        socket_path = Some(path);
+        socket
+    };
+    let http = if let Some(listener) = activated.http {
+        listener.set_nonblocking(true)?;
+        vec![webapp::listen::Listener::Tcp(tokio::net::TcpListener::from_std(listener)?)]
+    } else {
+        let bound = webapp::listen::bind(&settings.web.listen_addrs(), web_gid, settings.web.socket_mode()).await;
+        if bound.is_err() {
+            if let Some(path) = &socket_path {
+                _ = std::fs::remove_file(path);
+            }
+        }
+        bound?
+    };
     // set up app channels
-    let (cmd_tx, cmd_rx) = unbounded_channel::<DownloaderCommand>();
-    let (update_tx, update_rx) = broadcast::channel(1024);
+    let (cmd_tx, cmd_rx) = unbounded_channel::<commands::CommandRequest>();
+    let (update_tx, update_rx) = broadcast::channel(settings.downloader.update_buffer);
+    let (state_tx, state_rx) = tokio::sync::watch::channel(Tracker::with_settings(&settings.progress));
+    tokio::spawn(tracker::monitor(update_tx.subscribe(), state_tx));
+    let (internals_tx, internals_rx) = tokio::sync::watch::channel(downloader::Internals::default());
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let signals = shutdown::handle_signals(shutdown_tx, internals_rx.clone(), socket_path.clone());
+    tokio::spawn(async move {
+        if let Err(e) = signals.await {
+            error!("Could not set up signal handlers, signals stop the daemon without a shutdown: {e}");
+        }
+    });
+    let (settings_tx, settings_rx) = tokio::sync::watch::channel(settings.clone());
+    let (reload_tx, reload_rx) = unbounded_channel();
+    tokio::spawn(config::reloader(c, settings_tx, reload_rx, update_tx.clone()));
+    let history = open_history(&settings);
+    if let Some(history) = &history {
+        tokio::spawn(history::recorder(update_tx.subscribe(), history.clone(), settings.history.log_lines));
+        tokio::spawn(history::collector(update_tx.subscribe(), history.clone()));
+    }
     // start web server
     // let webserver_task = tokio::spawn(
     //     webapp::server(update_tx.subscribe(), &c)
     //     );
-    let web_ui = webapp::server(update_tx.subscribe(), c.port);
+    let probes = webapp::Probes { update_tx: update_tx.clone(), internals_rx };
+    let web_ui = webapp::server(
+        probes,
+        http,
+        shutdown_rx.clone(),
+        settings_rx.clone(),
+        cmd_tx.clone(),
+        state_rx.clone(),
+        history.clone(),
+    );
     // start unix socket
-    let unix_socket = unixsocket::server(socket, cmd_tx.clone());
+    let handlers = unixsocket::Handlers {
+        command: cmd_tx.clone(),
+        reload: reload_tx,
+        history,
+    };
+    let unix_socket = unixsocket::server(socket, handlers, state_rx, policy, shutdown_rx.clone());
     // testing harness
     // let testcode_fut = testcode::main(cmd_tx.clone(), update_tx.subscribe());
     // start the main downloader task
-    let main_thr = main_outer_loop(cmd_rx, update_tx);
-    tokio::join!(web_ui, main_thr, unix_socket);
-    unreachable!()
+    tokio::spawn(systemd::status_reporter(update_tx.subscribe()));
+    let main_thr = main_outer_loop(cmd_rx, update_tx, internals_tx, shutdown_rx, settings_rx);
+    systemd::notify("READY=1");
+    let (_, _, socket_result) = tokio::join!(web_ui, main_thr, unix_socket);
+    if let Err(e) = socket_result {
+        error!("Control socket failed: {e}");
+    }
+    if let Some(path) = socket_path {
+        _ = tokio::fs::remove_file(path).await;
+    }
+    info!("Shutdown complete");
+    Ok(())
+}
+
+/// The history database, unless it is disabled or cannot be opened. Downloads
+/// go on without it.
+fn open_history(settings: &Settings) -> Option<history::History> {
+    let path = settings.history.path.as_ref().filter(|_| settings.history.enabled)?;
+    match history::History::open(path) {
+        Ok(history) => {
+            info!("History database is {}", path.display());
+            Some(history)
+        }
+        Err(e) => {
+            error!("History disabled, could not open the database: {e}");
+            None
+        }
+    }
+}
+
+/// Prints the argon2 hash of the password on stdin, for `auth.users`
+fn hash_password() -> Anything<()> {
+    use argon2::password_hash::PasswordHasher;
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err("No password on stdin".into());
+    }
+    println!("{}", argon2::Argon2::default().hash_password(password.as_bytes())?);
+    Ok(())
 }
 
 pub fn humanize_bytes(x: u64) -> String {
@@ -1,4 +1,5 @@
 use crate::*;
+use crate::job::{Job, JobId, JobOptions, JobSpec};
 use std::{collections::VecDeque, task::Waker};
 
 pub struct AsyncQueue<T> {
@@ -19,35 +20,102 @@ impl<T: Clone> AsyncQueue<T> {
             waker.wake();
         }
     }
+    /// Puts an item back at the head of the queue
+    pub fn push_front(&mut self, url: impl Into<T>) {
+        self.queue.push_front(url.into());
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
     pub fn pop(&mut self) -> Option<T> {
         self.queue.pop_front()
     }
     pub fn len(&self) -> usize {
         self.queue.len()
     }
-    pub fn move_up(&mut self, index: usize) {
-        let len = self.len();
-        if len < 2 || index == 0 || index > (len - 1) {
-            return;
+    /// Returns false if there is no item at `index`
+    pub fn move_up(&mut self, index: usize) -> bool {
+        if index >= self.len() {
+            return false;
+        }
+        if index > 0 {
+            self.queue.swap(index, index - 1);
         }
-        self.queue.swap(index, index - 1);
+        true
     }
-    pub fn move_down(&mut self, index: usize) {
-        let len = self.len();
-        if len < 2 || index > (len - 2) {
-            return;
+    /// Returns false if there is no item at `index`
+    pub fn move_down(&mut self, index: usize) -> bool {
+        if index >= self.len() {
+            return false;
+        }
+        if index + 1 < self.len() {
+            self.queue.swap(index, index + 1);
         }
-        self.queue.swap(index, index + 1);
+        true
     }
-    pub fn remove(&mut self, index: usize) {
-        let len = self.len();
-        if len == 0 || index > (len - 1) {
-            return;
+    /// Moves the item at `from` to `to`, or to the end if `to` is past it
+    pub fn move_to(&mut self, from: usize, to: usize) -> bool {
+        let Some(item) = self.queue.remove(from) else {
+            return false;
+        };
+        let to = to.min(self.len());
+        self.queue.insert(to, item);
+        true
+    }
+    /// Moves the item at `from` right before the one at `target`, from above
+    /// or below it
+    pub fn move_before(&mut self, from: usize, target: usize) -> bool {
+        if from >= self.len() || target >= self.len() {
+            return false;
         }
-        self.queue.remove(index);
+        // the target moves up once the item above it is taken out
+        let to = if from < target { target - 1 } else { target };
+        self.move_to(from, to)
+    }
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        self.queue.remove(index)
     }
     pub fn contents(&self) -> Vec<T> {
-        self.queue.iter().map(|x| x.clone() ).collect()
+        self.queue.iter().cloned().collect()
+    }
+}
+
+impl AsyncQueue<Job> {
+    pub fn position(&self, id: JobId) -> Option<usize> {
+        self.queue.iter().position(|job| job.id == id)
+    }
+    pub fn get_mut(&mut self, id: JobId) -> Option<&mut Job> {
+        self.queue.iter_mut().find(|job| job.id == id)
+    }
+    /// Reads a queue saved by [`AsyncQueue::save`], one JSON job per line.
+    /// Plain URL lines from older versions are accepted too.
+    pub async fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let contents = tokio::fs::read_to_string(path).await?;
+        let mut q = Self::new();
+        for line in contents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if line.starts_with('{') {
+                let spec: JobSpec = serde_json::from_str(line)?;
+                q.push(spec);
+            } else {
+                q.push(Job::new(line, JobOptions::default()));
+            }
+        }
+        Ok(q)
+    }
+    pub async fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let mut contents = String::new();
+        for job in &self.queue {
+            contents.push_str(&serde_json::to_string(&job.spec())?);
+            contents.push('\n');
+        }
+        // write and rename, so a crash never leaves half a queue behind
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, contents).await?;
+        tokio::fs::rename(tmp, path).await
     }
 }
 
@@ -72,3 +140,28 @@ impl<T: Unpin + Clone> Stream for AsyncQueue<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+
+    #[test]
+    fn check_move_before() {
+        let mut q = AsyncQueue::<u8>::new();
+        for n in 0..5 {
+            q.push(n);
+        }
+        // down: 1 before 4
+        assert!(q.move_before(1, 4));
+        assert_eq!(q.contents(), [0, 2, 3, 1, 4]);
+        // up: 4 before 2
+        assert!(q.move_before(4, 1));
+        assert_eq!(q.contents(), [0, 4, 2, 3, 1]);
+        // before itself, and before the next one, changes nothing
+        assert!(q.move_before(2, 2));
+        assert!(q.move_before(2, 3));
+        assert_eq!(q.contents(), [0, 4, 2, 3, 1]);
+        assert!(!q.move_before(5, 0));
+        assert!(!q.move_before(0, 5));
+    }
+}
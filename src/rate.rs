@@ -0,0 +1,292 @@
+//! Download rate estimators. Each one follows the progress of a download and
+//! tells how fast it goes; `progress.rate_estimator` picks which one the
+//! daemon uses.
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use tokio::time::{Duration, Instant};
+
+use crate::config::ProgressSettings;
+
+pub trait RateEstimator {
+    /// A progress report: the bytes of the current stream so far, and the
+    /// speed the downloader reported, if it did
+    fn push(&mut self, bytes: u64, reported: Option<u64>, now: Instant);
+    /// Bytes per second, if there is enough to tell
+    fn rate(&mut self, now: Instant) -> Option<u64>;
+    /// Forgets everything, for a download that starts over or stopped
+    fn reset(&mut self);
+}
+
+/// Turns the bytes of each stream into bytes of the whole download. yt-dlp
+/// counts every stream, e.g. video and audio, from zero.
+#[derive(Debug, Default, Clone)]
+pub struct Counter {
+    /// Bytes of the streams that are done
+    done: u64,
+    last: u64,
+}
+
+impl Counter {
+    /// The bytes of the whole download after a report
+    pub fn total(&mut self, bytes: u64) -> u64 {
+        if bytes < self.last {
+            self.done += self.last;
+        }
+        self.last = bytes;
+        self.done + bytes
+    }
+    /// The bytes a report adds
+    pub fn delta(&mut self, bytes: u64) -> u64 {
+        let before = self.bytes();
+        self.total(bytes) - before
+    }
+    /// The bytes of the whole download so far
+    pub fn bytes(&self) -> u64 {
+        self.done + self.last
+    }
+}
+
+/// The bytes between the oldest and the newest report within `max`, over
+/// the time between them. The reported speed until the reports span `min`.
+#[derive(Debug, Default, Clone)]
+pub struct SlidingWindow {
+    samples: VecDeque<(Instant, u64)>,
+    counter: Counter,
+    reported: Option<u64>,
+    min: Duration,
+    max: Duration,
+}
+
+impl SlidingWindow {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self { min, max, ..Self::default() }
+    }
+    fn drop_old(&mut self, now: Instant) {
+        while self.samples.front().is_some_and(|(t, _)| now.saturating_duration_since(*t) > self.max) {
+            self.samples.pop_front();
+        }
+    }
+}
+
+impl RateEstimator for SlidingWindow {
+    fn push(&mut self, bytes: u64, reported: Option<u64>, now: Instant) {
+        let total = self.counter.total(bytes);
+        self.samples.push_back((now, total));
+        self.reported = reported;
+        self.drop_old(now);
+    }
+    fn rate(&mut self, now: Instant) -> Option<u64> {
+        self.drop_old(now);
+        let (t1, b1) = self.samples.front()?;
+        let (t2, b2) = self.samples.back()?;
+        let elapsed = t2.saturating_duration_since(*t1);
+        if elapsed < self.min || elapsed.is_zero() {
+            return self.reported;
+        }
+        Some((b2.saturating_sub(*b1) as f64 / elapsed.as_secs_f64()) as u64)
+    }
+    fn reset(&mut self) {
+        self.samples.clear();
+        self.counter = Counter::default();
+        self.reported = None;
+    }
+}
+
+/// An exponentially weighted moving average: a report from `half_life` ago
+/// weighs half as much as one from now. Time without reports counts as time
+/// without progress.
+#[derive(Debug, Default, Clone)]
+pub struct Ewma {
+    half_life: Duration,
+    counter: Counter,
+    last: Option<(Instant, u64)>,
+    rate: Option<f64>,
+}
+
+impl Ewma {
+    pub fn new(half_life: Duration) -> Self {
+        Self { half_life, ..Self::default() }
+    }
+}
+
+impl RateEstimator for Ewma {
+    fn push(&mut self, bytes: u64, _: Option<u64>, now: Instant) {
+        let total = self.counter.total(bytes);
+        let Some((t, b)) = self.last else {
+            self.last = Some((now, total));
+            return;
+        };
+        let elapsed = now.saturating_duration_since(t);
+        // reports at the same instant are counted with the next one
+        if elapsed.is_zero() {
+            return;
+        }
+        let current = total.saturating_sub(b) as f64 / elapsed.as_secs_f64();
+        let weight = 1.0 - 0.5f64.powf(elapsed.as_secs_f64() / self.half_life.as_secs_f64());
+        self.rate = Some(match self.rate {
+            Some(rate) => rate + weight * (current - rate),
+            None => current,
+        });
+        self.last = Some((now, total));
+    }
+    fn rate(&mut self, now: Instant) -> Option<u64> {
+        let (t, _) = self.last?;
+        let elapsed = now.saturating_duration_since(t);
+        let decay = 0.5f64.powf(elapsed.as_secs_f64() / self.half_life.as_secs_f64());
+        self.rate.map(|r| (r * decay) as u64)
+    }
+    fn reset(&mut self) {
+        *self = Self::new(self.half_life);
+    }
+}
+
+/// The speed yt-dlp reports, which it smooths itself
+#[derive(Debug, Default, Clone)]
+pub struct Reported {
+    speed: Option<u64>,
+}
+
+impl RateEstimator for Reported {
+    fn push(&mut self, _: u64, reported: Option<u64>, _: Instant) {
+        self.speed = reported;
+    }
+    fn rate(&mut self, _: Instant) -> Option<u64> {
+        self.speed
+    }
+    fn reset(&mut self) {
+        self.speed = None;
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EstimatorKind {
+    #[default]
+    Sliding,
+    Ewma,
+    Reported,
+}
+
+/// The estimator chosen in the settings
+#[derive(Debug, Clone)]
+pub enum Estimator {
+    Sliding(SlidingWindow),
+    Ewma(Ewma),
+    Reported(Reported),
+}
+
+impl Default for Estimator {
+    fn default() -> Self {
+        Self::new(&ProgressSettings::default())
+    }
+}
+
+impl Estimator {
+    pub fn new(settings: &ProgressSettings) -> Self {
+        match settings.rate_estimator {
+            EstimatorKind::Sliding => Self::Sliding(SlidingWindow::new(
+                Duration::from_millis(settings.rate_window_min_ms),
+                Duration::from_millis(settings.rate_window_max_ms),
+            )),
+            EstimatorKind::Ewma => Self::Ewma(Ewma::new(Duration::from_millis(settings.rate_half_life_ms))),
+            EstimatorKind::Reported => Self::Reported(Reported::default()),
+        }
+    }
+    fn inner(&mut self) -> &mut dyn RateEstimator {
+        match self {
+            Self::Sliding(e) => e,
+            Self::Ewma(e) => e,
+            Self::Reported(e) => e,
+        }
+    }
+}
+
+impl RateEstimator for Estimator {
+    fn push(&mut self, bytes: u64, reported: Option<u64>, now: Instant) {
+        self.inner().push(bytes, reported, now)
+    }
+    fn rate(&mut self, now: Instant) -> Option<u64> {
+        self.inner().rate(now)
+    }
+    fn reset(&mut self) {
+        self.inner().reset()
+    }
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    /// Pushes `bytes` after each second
+    async fn feed(e: &mut impl RateEstimator, bytes: &[u64]) -> Option<u64> {
+        for b in bytes {
+            tokio::time::advance(SEC).await;
+            e.push(*b, None, Instant::now());
+        }
+        e.rate(Instant::now())
+    }
+
+    #[tokio::test]
+    async fn check_sliding() {
+        tokio::time::pause();
+        let mut e = SlidingWindow::new(2 * SEC, 4 * SEC);
+        e.push(0, None, Instant::now());
+        assert_eq!(feed(&mut e, &[100]).await, None);
+        assert_eq!(feed(&mut e, &[200]).await, Some(100));
+        // the oldest reports fall out of the window
+        assert_eq!(feed(&mut e, &[300, 400, 1400]).await, Some(325));
+        // a new stream does not start over
+        assert_eq!(feed(&mut e, &[100, 600]).await, Some(425));
+        tokio::time::advance(10 * SEC).await;
+        assert_eq!(e.rate(Instant::now()), None);
+        e.reset();
+        assert_eq!(feed(&mut e, &[5]).await, None);
+        // the downloader's own guess until the window is wide enough
+        e.push(10, Some(80), Instant::now());
+        assert_eq!(e.rate(Instant::now()), Some(80));
+        e.push(2005, Some(90), Instant::now() + 2 * SEC);
+        assert_eq!(e.rate(Instant::now() + 2 * SEC), Some(1000));
+    }
+
+    #[tokio::test]
+    async fn check_ewma() {
+        tokio::time::pause();
+        let mut e = Ewma::new(SEC);
+        assert_eq!(feed(&mut e, &[0]).await, None);
+        assert_eq!(feed(&mut e, &[1000]).await, Some(1000));
+        // a report a half-life later weighs half
+        assert_eq!(feed(&mut e, &[1000]).await, Some(500));
+        e.push(1000, None, Instant::now());
+        assert_eq!(feed(&mut e, &[1500, 500]).await, Some(500));
+        // a half-life without reports
+        tokio::time::advance(SEC).await;
+        assert_eq!(e.rate(Instant::now()), Some(250));
+        e.reset();
+        assert_eq!(feed(&mut e, &[10]).await, None);
+    }
+
+    #[test]
+    fn check_counter() {
+        let mut c = Counter::default();
+        assert_eq!(c.delta(300), 300);
+        assert_eq!(c.delta(500), 200);
+        // the audio stream after the video
+        assert_eq!(c.total(100), 600);
+        assert_eq!(c.delta(150), 50);
+        assert_eq!(c.bytes(), 650);
+    }
+
+    #[tokio::test]
+    async fn check_estimator() {
+        tokio::time::pause();
+        let settings = ProgressSettings { rate_estimator: EstimatorKind::Reported, ..ProgressSettings::default() };
+        let mut e = Estimator::new(&settings);
+        e.push(10, Some(250), Instant::now());
+        assert_eq!(e.rate(Instant::now()), Some(250));
+        e.reset();
+        assert_eq!(e.rate(Instant::now()), None);
+        assert!(matches!(Estimator::default(), Estimator::Sliding(_)));
+    }
+}
@@ -0,0 +1,72 @@
+use crate::*;
+use crate::downloader::Internals;
+use std::path::{Path, PathBuf};
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+/// Flips the shutdown flag on the first SIGTERM or SIGINT. A second signal
+/// exits immediately with 128 plus its number, without waiting for the
+/// downloader. It still kills the downloader and removes the control socket,
+/// if it was created here.
+pub async fn handle_signals(
+    shutdown_tx: watch::Sender<bool>,
+    internals_rx: watch::Receiver<Internals>,
+    socket_path: Option<PathBuf>,
+) -> std::io::Result<()> {
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    select! {
+        _ = term.recv() => info!("SIGTERM received, shutting down"),
+        _ = int.recv() => info!("SIGINT received, shutting down"),
+    }
+    systemd::notify("STOPPING=1");
+    _ = shutdown_tx.send(true);
+    let signo = select! {
+        _ = term.recv() => libc::SIGTERM,
+        _ = int.recv() => libc::SIGINT,
+    };
+    warn!("Second signal received, exiting immediately");
+    abort(&internals_rx.borrow(), socket_path.as_deref());
+    std::process::exit(128 + signo);
+}
+
+/// What has to be undone even when exiting without a shutdown
+fn abort(internals: &Internals, socket_path: Option<&Path>) {
+    if let Some(pid) = internals.pid {
+        // SAFETY: plain kill(2) on the pid of our own child process
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+    }
+    if let Some(path) = socket_path {
+        _ = std::fs::remove_file(path);
+    }
+}
+
+/// Completes once shutdown has been requested. Never completes if the
+/// sender is gone without requesting it.
+pub async fn requested(shutdown_rx: &mut watch::Receiver<bool>) {
+    if shutdown_rx.wait_for(|s| *s).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn check_abort() {
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        let path = std::env::temp_dir().join(format!("downd-abort-{}.sock", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let internals = Internals { pid: Some(child.id()), ..Internals::default() };
+        abort(&internals, Some(&path));
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+        assert!(!path.exists());
+        // nothing to do before the downloader started
+        abort(&Internals::default(), None);
+    }
+}
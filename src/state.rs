@@ -0,0 +1,95 @@
+//! What the daemon is doing, as the downloader reports it with
+//! `DownloaderMsg::StateChanged`. Serialized with a `kind` tag, so clients
+//! can switch on it without parsing text.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Why the downloader waits for the user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum HoldReason {
+    /// Paused by the user
+    User,
+    /// The downloader exited with an error code
+    ExitCode { code: i32 },
+    /// The downloader could not be started or read from
+    Io { error: String },
+    /// The downloader was killed by a signal
+    Killed,
+}
+
+impl HoldReason {
+    /// Every hold but a pause is caused by an error
+    pub fn is_error(&self) -> bool {
+        *self != HoldReason::User
+    }
+}
+
+impl fmt::Display for HoldReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HoldReason::User => write!(f, "User hold"),
+            HoldReason::ExitCode { code } => write!(f, "Error code {code}"),
+            HoldReason::Io { error } => write!(f, "IO error: {error}"),
+            HoldReason::Killed => write!(f, "Downloader killed"),
+        }
+    }
+}
+
+/// What the downloader is doing. There is no state for a pending retry:
+/// failed downloads hold until the user retries, which starts right away.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DaemonState {
+    /// Waiting for the queue
+    #[default]
+    Idle,
+    /// The downloader runs, but has not reported progress yet
+    Starting,
+    Downloading,
+    /// The download is done and yt-dlp moves or converts the files
+    PostProcessing,
+    /// The downloader has been quiet for longer than the stuck timeout
+    Stuck,
+    Held { reason: HoldReason },
+    ShuttingDown,
+}
+
+impl DaemonState {
+    pub fn hold_reason(&self) -> Option<&HoldReason> {
+        match self {
+            DaemonState::Held { reason } => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DaemonState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DaemonState::Idle => write!(f, "Idle"),
+            DaemonState::Starting => write!(f, "Starting"),
+            DaemonState::Downloading => write!(f, "Downloading"),
+            DaemonState::PostProcessing => write!(f, "Post-processing"),
+            DaemonState::Stuck => write!(f, "Stuck"),
+            DaemonState::Held { reason } => write!(f, "Holding: {reason}"),
+            DaemonState::ShuttingDown => write!(f, "Shutting down"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+
+    #[test]
+    fn check_serialize() {
+        let held = DaemonState::Held { reason: HoldReason::ExitCode { code: 2 } };
+        let json = serde_json::to_string(&held).unwrap();
+        assert_eq!(json, r#"{"kind":"held","reason":{"kind":"exit_code","code":2}}"#);
+        assert_eq!(serde_json::from_str::<DaemonState>(&json).unwrap(), held);
+        assert_eq!(serde_json::to_string(&DaemonState::PostProcessing).unwrap(), r#"{"kind":"post_processing"}"#);
+        assert_eq!(held.to_string(), "Holding: Error code 2");
+        assert!(!HoldReason::User.is_error());
+    }
+}
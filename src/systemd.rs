@@ -0,0 +1,164 @@
+//! Socket activation and readiness notification, following the protocol of
+//! sd_listen_fds(3) and sd_notify(3) without linking libsystemd.
+use crate::*;
+use std::os::{
+    fd::{FromRawFd, IntoRawFd, RawFd},
+    unix::net::{UnixDatagram, UnixListener as StdUnixListener},
+};
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+/// The first file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Listening sockets handed over by the service manager
+#[derive(Default)]
+pub struct ListenFds {
+    pub control: Option<StdUnixListener>,
+    pub http: Option<std::net::TcpListener>,
+}
+
+/// The `LISTEN_*` variables, taken out of the environment at startup
+pub struct ListenEnv {
+    pid: Option<u32>,
+    count: Option<RawFd>,
+    names: String,
+}
+
+impl ListenEnv {
+    /// Reads and removes the variables so the downloader processes do not
+    /// inherit them. Must be called before any other thread is started, as
+    /// changing the environment races with everything that reads it.
+    pub fn take() -> Self {
+        let env = Self {
+            pid: std::env::var("LISTEN_PID").ok().and_then(|p| p.parse().ok()),
+            count: std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse().ok()),
+            names: std::env::var("LISTEN_FDNAMES").unwrap_or_default(),
+        };
+        for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(var);
+        }
+        env
+    }
+}
+
+/// Takes over the sockets passed in `LISTEN_FDS`. Sockets named "control" or
+/// "http" in `LISTEN_FDNAMES` are used for that purpose, unnamed sockets are
+/// assigned by address family.
+pub fn listen_fds(env: ListenEnv) -> ListenFds {
+    let mut fds = ListenFds::default();
+    let ListenEnv { pid, count, names } = env;
+    let (Some(pid), Some(count)) = (pid, count) else {
+        return fds;
+    };
+    if pid != std::process::id() {
+        return fds;
+    }
+    let mut names = names.split(':');
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        let name = names.next().unwrap_or_default();
+        // SAFETY: systemd passes these descriptors to us and nothing else
+        // in the process owns them
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        let unix = unsafe { StdUnixListener::from_raw_fd(fd) };
+        let is_unix = unix.local_addr().is_ok();
+        match name {
+            "control" | "" if is_unix && fds.control.is_none() => {
+                info!("Using socket activated control socket (fd {fd})");
+                fds.control = Some(unix);
+            }
+            "http" | "" if !is_unix && fds.http.is_none() => {
+                info!("Using socket activated http socket (fd {fd})");
+                let fd = unix.into_raw_fd();
+                fds.http = Some(unsafe { std::net::TcpListener::from_raw_fd(fd) });
+            }
+            _ => {
+                warn!("Ignoring passed socket {name:?} (fd {fd})");
+                // leave it open, systemd still holds a reference anyway
+                _ = unix.into_raw_fd();
+            }
+        }
+    }
+    fds
+}
+
+/// Sends a state update to the service manager, if there is one
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let Ok(sock) = UnixDatagram::unbound() else {
+        return;
+    };
+    let path = path.to_string_lossy();
+    let result = if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        std::os::unix::net::SocketAddr::from_abstract_name(name)
+            .and_then(|addr| sock.send_to_addr(state.as_bytes(), &addr))
+    } else {
+        sock.send_to(state.as_bytes(), path.as_ref())
+    };
+    if let Err(e) = result {
+        debug!("sd_notify failed: {e}");
+    }
+}
+
+/// Pings the service manager's watchdog at half the interval it asked for
+pub struct Watchdog {
+    interval: Option<Interval>,
+}
+
+impl Watchdog {
+    pub fn from_env() -> Self {
+        let pid_matches = std::env::var("WATCHDOG_PID")
+            .map(|p| p.parse() == Ok(std::process::id()))
+            .unwrap_or(true);
+        let usec = std::env::var("WATCHDOG_USEC").ok().and_then(|u| u.parse::<u64>().ok());
+        let interval = usec.filter(|u| pid_matches && *u > 0).map(|usec| {
+            let mut i = interval(Duration::from_micros(usec / 2));
+            i.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            i
+        });
+        Self { interval }
+    }
+    /// Completes after sending a ping. Never completes if the watchdog is
+    /// not enabled.
+    pub async fn ping(&mut self) {
+        match &mut self.interval {
+            Some(i) => {
+                i.tick().await;
+                notify("WATCHDOG=1");
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Mirrors the downloader state into the service's status line
+pub async fn status_reporter(mut update_rx: broadcast::Receiver<DownloaderMsg>) {
+    use broadcast::error::RecvError;
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    let mut state = DaemonState::Idle;
+    let mut title: Option<String> = None;
+    loop {
+        let msg = match update_rx.recv().await {
+            Ok(msg) => msg,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+        match msg {
+            DownloaderMsg::StateChanged(s) => state = s,
+            DownloaderMsg::Starting(t) => title = t,
+            DownloaderMsg::Idle => title = None,
+            _ => continue,
+        }
+        let status = match (&state, &title) {
+            (DaemonState::Starting | DaemonState::Downloading | DaemonState::PostProcessing, Some(title)) => {
+                format!("{state}: {title}")
+            }
+            _ => state.to_string(),
+        };
+        notify(&format!("STATUS={status}"));
+    }
+}
@@ -6,16 +6,16 @@ use tokio::{
     time::{sleep, Duration},
 };
 use tokio_stream::Stream;
-use rollingrate::RollingRate;
+use rate::{RateEstimator, SlidingWindow};
 
 async fn test_command(
     cmd: DownloaderCommand,
-    chan: &UnboundedSender<DownloaderCommand>,
+    chan: &UnboundedSender<commands::CommandRequest>,
     delay: Duration,
 ) {
     sleep(delay).await;
     trace!("Sending {cmd:?}");
-    _ = chan.send(cmd);
+    _ = chan.send(cmd.into());
 }
 fn secs(n: u64) -> Duration {
     Duration::from_secs(n)
@@ -26,7 +26,7 @@ fn millis(n: u64) -> Duration {
 
 async fn reporter(mut update_rx: broadcast::Receiver<DownloaderMsg>) {
     debug!("reporter loop running");
-    let mut avg = RollingRate::new(Duration::from_millis(2000), Duration::from_millis(10300));
+    let mut avg = SlidingWindow::new(Duration::from_millis(2000), Duration::from_millis(10300));
     loop {
         let msg = update_rx.recv().await;
         if let Ok(msg) = msg {
@@ -35,8 +35,8 @@ async fn reporter(mut update_rx: broadcast::Receiver<DownloaderMsg>) {
                 Starting(Some(url)) => debug!("Starting download of {url}"),
                 Downloading { downloaded_bytes, .. } => {
                     let p = msg.progress().map(|x| x * 100.0);
-                    avg.push(downloaded_bytes);
-                    let r = avg.rate().map(|x| format!("{}/s", humanize_bytes(x)));
+                    avg.push(downloaded_bytes, None, tokio::time::Instant::now());
+                    let r = avg.rate(tokio::time::Instant::now()).map(|x| format!("{}/s", humanize_bytes(x)));
                     debug!("{p:.2?} | rate: {r:?}")
                 }
                 Moved(Some(url)) => {
@@ -55,7 +55,7 @@ async fn reporter(mut update_rx: broadcast::Receiver<DownloaderMsg>) {
     }
 }
 
-pub async fn main(cmd_tx: UnboundedSender<DownloaderCommand>,
+pub async fn main(cmd_tx: UnboundedSender<commands::CommandRequest>,
                   mut update_rx: broadcast::Receiver<DownloaderMsg>,
                   ) -> Anything<()> {
 
@@ -1,41 +1,89 @@
-mod rollingrate;
-use rollingrate::RollingRate;
+mod ratehistory;
+pub use ratehistory::RateHistory;
 use crate::*;
-use askama::Template;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use crate::config::ProgressSettings;
+use crate::history::Status;
+use crate::job::Job;
+use crate::rate::{Counter, Estimator, RateEstimator};
 
-#[derive(Template, Default)]
-#[template(path = "sse_update.html")]
+/// Number of finished downloads kept for display
+const HISTORY_LEN: usize = 20;
+
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct Tracker {
     pub title: Option<String>,
-    pub state: String,
+    pub state: DaemonState,
+    /// Why the downloader is holding, if it is. The state tells the same,
+    /// this is for clients of the first status format.
+    pub hold: Option<String>,
     pub progress: Option<f64>,
     rate: Option<u64>,
     pub rate_h: Option<String>,
-    pub queue: Vec<String>,
+    /// The job being downloaded, if any
+    pub current: Option<Job>,
+    pub queue: Vec<Job>,
     pub total_bytes: Option<u64>,
     downloaded_bytes: u64,
     pub eta: Option<u64>,
-    rolling_rate: RollingRate,
+    /// Seconds until the queue is done, guessing that the queued jobs are
+    /// as big as the ones finished so far
+    pub queue_eta: Option<u64>,
+    /// Most recently finished downloads, newest first
+    pub history: VecDeque<HistoryEntry>,
+    /// The rate over the last minutes
+    pub rate_history: RateHistory,
+    #[serde(skip)]
+    estimator: Estimator,
+    /// Bytes of the current job
+    #[serde(skip)]
+    counter: Counter,
+    /// Count and total size of the jobs finished since the start
+    #[serde(skip)]
+    finished: (u64, u64),
+    /// Jobs that ended since the start, the newest of them are in the history
+    #[serde(skip)]
+    pub ended: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub job: Job,
+    pub title: Option<String>,
+    pub status: Status,
+    pub reason: String,
 }
 
 impl Tracker {
     pub fn new() -> Self {
-        let mut x = Self::default();
-        x.state = "Idle".into();
-        x.rolling_rate = RollingRate::new(Duration::from_millis(1500), Duration::from_secs(15));
-        x
+        Self::with_settings(&ProgressSettings::default())
     }
-    pub fn update(&mut self, msg: DownloaderMsg) {
+    pub fn with_settings(settings: &ProgressSettings) -> Self {
+        Self {
+            estimator: Estimator::new(settings),
+            rate_history: RateHistory::new(
+                Duration::from_millis(settings.rate_history_interval_ms),
+                Duration::from_secs(settings.rate_history_secs),
+            ),
+            ..Default::default()
+        }
+    }
+    /// Returns whether the message changed anything watchers see
+    pub fn update(&mut self, msg: DownloaderMsg) -> bool {
         use DownloaderMsg::*;
+        let now = Instant::now();
+        let changes = !matches!(msg, Moved(_) | ConfigReloaded(_) | PostProcessing | Saved(_) | Output(_) | Info(_));
         match msg {
             Starting(title) => {
-                self.state = "Starting".into();
                 self.title = title;
             },
-            Downloading { downloaded_bytes, total_bytes, .. } => {
-                self.rolling_rate.push(downloaded_bytes);
-                self.rate = self.rolling_rate.rate();
-                self.state = "Downloading".into();
+            Downloading { downloaded_bytes, total_bytes, speed, .. } => {
+                self.estimator.push(downloaded_bytes, speed, now);
+                self.rate = self.estimator.rate(now);
+                self.rate_history.add(self.counter.delta(downloaded_bytes), now);
                 self.progress = msg.progress();
                 self.total_bytes = total_bytes;
                 self.downloaded_bytes = downloaded_bytes;
@@ -45,33 +93,115 @@ impl Tracker {
                 // self.state = "Finishing".into();
             },
             Stuck => {
-                self.state = "Stuck".into();
                 self.progress = None;
-                self.rolling_rate.reset();
+                self.estimator.reset();
+                self.rate = None;
+                self.calculate();
             },
             Idle => {
-                self.state = "Idle".into();
                 self.progress = None;
                 self.title = None;
-                self.rate_h = None;
-                self.rolling_rate.reset();
+                self.current = None;
+                self.estimator.reset();
+                self.rate = None;
+                self.calculate();
             },
-            Hold(reason) => {
-                self.state = format!("Holding: {reason}");
-                self.rolling_rate.reset();
+            Hold(_) => {
+                self.estimator.reset();
+                self.rate = None;
+                self.calculate();
             },
-            QueueUpdate(urls) => {
-                self.queue = urls;
+            QueueUpdate(jobs) => {
+                self.queue = jobs;
+                self.calculate();
+            }
+            Current(job) => {
+                self.current = Some(job);
+                self.counter = Counter::default();
+                self.downloaded_bytes = 0;
+            }
+            StateChanged(state) => {
+                self.hold = state.hold_reason().map(ToString::to_string);
+                self.state = state;
+            }
+            // the download starts over
+            Spawned { .. } => {
+                self.counter = Counter::default();
+                self.downloaded_bytes = 0;
+            }
+            ConfigReloaded(_) | PostProcessing | Saved(_) | Output(_) | Info(_) => {}
+            Finished { job, status, reason } => {
+                self.current = None;
+                if status == Status::Finished {
+                    self.finished.0 += 1;
+                    self.finished.1 += self.counter.bytes();
+                }
+                self.history.push_front(HistoryEntry {
+                    job,
+                    title: self.title.clone(),
+                    status,
+                    reason,
+                });
+                self.history.truncate(HISTORY_LEN);
+                self.ended += 1;
             }
         }
+        changes
     }
     /// Evaluates the calculated fields
     pub fn calculate(&mut self) {
-        self.rate_h = humanize_rate(self.rate); 
-        if let Some(r) = self.rate {
-            if let Some(t) = self.total_bytes {
-                self.eta = Some( (t - self.downloaded_bytes) / r );
-                return;
+        self.rate_h = humanize_rate(self.rate);
+        match self.rate {
+            Some(r) if r > 0 => {
+                if let Some(t) = self.total_bytes {
+                    self.eta = Some( t.saturating_sub(self.downloaded_bytes) / r );
+                }
+            }
+            // keep the last guess through a stall, but not without a rate
+            Some(_) => {}
+            None => self.eta = None,
+        }
+        self.queue_eta = self.queue_remaining().and_then(|b| Some(b / self.rate.filter(|r| *r > 0)?));
+    }
+    /// Bytes left until the queue is done, if there is a guess
+    fn queue_remaining(&self) -> Option<u64> {
+        self.current.as_ref()?;
+        let current = self.total_bytes?.saturating_sub(self.downloaded_bytes);
+        if self.queue.is_empty() {
+            return Some(current);
+        }
+        let (count, bytes) = self.finished;
+        let average = bytes.checked_div(count).filter(|a| *a > 0)?;
+        Some(current + average * self.queue.len() as u64)
+    }
+    /// Smoothed download rate in bytes per second
+    pub fn rate(&self) -> Option<u64> {
+        self.rate
+    }
+    /// Downloaded bytes of the current download, for clients that render
+    /// their own progress display
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.downloaded_bytes
+    }
+}
+
+/// Keeps a tracker up to date with the downloader's messages and publishes
+/// every change on the watch channel. The rate history moves on every
+/// interval, downloading or not.
+pub async fn monitor(mut update_rx: broadcast::Receiver<DownloaderMsg>, state_tx: watch::Sender<Tracker>) {
+    use broadcast::error::RecvError;
+    debug!("tracker monitor started");
+    let mut sample = tokio::time::interval(state_tx.borrow().rate_history.interval());
+    sample.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            msg = update_rx.recv() => match msg {
+                Ok(msg) => _ = state_tx.send_if_modified(|t| t.update(msg)),
+                Err(RecvError::Lagged(n)) => warn!("tracker monitor skipped {n} updates"),
+                Err(RecvError::Closed) => break,
+            },
+            _ = sample.tick() => {
+                _ = state_tx.send_if_modified(|t| t.rate_history.advance(Instant::now()));
             }
         }
     }
@@ -80,3 +210,50 @@ impl Tracker {
 fn humanize_rate(r: Option<u64>) -> Option<String> {
     r.map(|r| format!("{}/s", humanize_bytes(r)))
 }
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+    use crate::job::JobOptions;
+
+    #[tokio::test]
+    async fn check_queue_eta() {
+        tokio::time::pause();
+        let job = |n: u8| Job::new(format!("https://example.com/{n}").as_str(), JobOptions::default());
+        let progress = |downloaded_bytes| DownloaderMsg::Downloading {
+            downloaded_bytes,
+            total_bytes: Some(1000),
+            frag_index: None,
+            frag_count: None,
+            speed: None,
+        };
+        let settings = ProgressSettings { rate_window_min_ms: 1000, ..ProgressSettings::default() };
+        let mut t = Tracker::with_settings(&settings);
+        t.update(DownloaderMsg::QueueUpdate(vec![job(2), job(3)]));
+        t.update(DownloaderMsg::Current(job(1)));
+        t.update(progress(0));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        // video and audio streams
+        t.update(progress(1000));
+        t.update(progress(0));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        t.update(progress(500));
+        assert_eq!(t.rate(), Some(750));
+        assert_eq!(t.eta, Some(0));
+        // no job finished yet to guess from
+        assert_eq!(t.queue_eta, None);
+        t.update(progress(1000));
+        t.update(DownloaderMsg::Finished { job: job(1), status: Status::Finished, reason: "Finished".into() });
+        t.update(DownloaderMsg::QueueUpdate(vec![job(3)]));
+        t.update(DownloaderMsg::Current(job(2)));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        t.update(progress(250));
+        // 750 of this job and 2000 for the next
+        assert_eq!(t.queue_eta, Some(2750 / t.rate().unwrap()));
+        t.update(DownloaderMsg::QueueUpdate(vec![]));
+        assert_eq!(t.queue_eta, t.eta);
+        t.update(DownloaderMsg::Idle);
+        assert_eq!(t.queue_eta, None);
+        assert_eq!((t.rate(), t.eta), (None, None));
+    }
+}
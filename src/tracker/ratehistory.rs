@@ -0,0 +1,138 @@
+//! The download rate over the last minutes, one sample per interval, to see
+//! patterns like a site that slows down after the first megabytes
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{Duration, Instant};
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateHistory {
+    /// Length of a sample
+    pub interval_ms: u64,
+    /// Bytes per second downloaded in each interval, oldest first
+    pub samples: VecDeque<u64>,
+    /// End of the newest sample, in milliseconds since the epoch
+    pub newest_ms: Option<u64>,
+    #[serde(skip)]
+    capacity: usize,
+    /// Start of the interval being counted
+    #[serde(skip)]
+    start: Option<Instant>,
+    /// Bytes downloaded in the interval being counted
+    #[serde(skip)]
+    pending: u64,
+}
+
+impl RateHistory {
+    /// Samples of `interval` covering `span`
+    pub fn new(interval: Duration, span: Duration) -> Self {
+        let interval_ms = interval.as_millis().max(1) as u64;
+        let capacity = (span.as_millis() as u64 / interval_ms) as usize;
+        Self {
+            interval_ms,
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+            ..Self::default()
+        }
+    }
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+    fn push(&mut self, rate: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rate);
+    }
+    /// Counts `bytes` downloaded at `now`
+    pub fn add(&mut self, bytes: u64, now: Instant) {
+        self.advance(now);
+        self.pending += bytes;
+    }
+    /// Ends the intervals that are over at `now`, returning whether there
+    /// are new samples. Intervals without any bytes are zero, so stalls and
+    /// idle times show.
+    pub fn advance(&mut self, now: Instant) -> bool {
+        let Some(start) = self.start else {
+            self.start = Some(now);
+            return false;
+        };
+        let intervals = now.saturating_duration_since(start).as_millis() as u64 / self.interval_ms;
+        if intervals == 0 {
+            return false;
+        }
+        self.push(self.pending * 1000 / self.interval_ms);
+        self.pending = 0;
+        // one more than fits pushes the counted interval out as well
+        for _ in 1..intervals.min(self.capacity as u64 + 1) {
+            self.push(0);
+        }
+        let end = start + Duration::from_millis(intervals * self.interval_ms);
+        self.start = Some(end);
+        self.newest_ms = SystemTime::now()
+            .checked_sub(now.saturating_duration_since(end))
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as u64);
+        true
+    }
+    pub fn max(&self) -> u64 {
+        self.samples.iter().copied().max().unwrap_or(0)
+    }
+    /// The highest rate, for people
+    pub fn peak(&self) -> String {
+        format!("{}/s", crate::humanize_bytes(self.max()))
+    }
+    /// Points of an SVG polyline `width` by `height` in size, the newest
+    /// sample at the right edge
+    pub fn points(&self, width: u32, height: u32) -> String {
+        let max = self.max().max(1) as f64;
+        let step = width as f64 / self.capacity.max(2).saturating_sub(1) as f64;
+        let offset = self.capacity.saturating_sub(self.samples.len());
+        let mut points = String::new();
+        for (i, rate) in self.samples.iter().enumerate() {
+            let x = (offset + i) as f64 * step;
+            let y = height as f64 * (1.0 - *rate as f64 / max);
+            _ = write!(points, "{x:.1},{y:.1} ");
+        }
+        points.trim_end().to_string()
+    }
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+
+    #[test]
+    fn check_record() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut h = RateHistory::new(Duration::from_secs(1), Duration::from_secs(5));
+        h.add(100, at(0));
+        h.add(200, at(900));
+        assert!(h.samples.is_empty());
+        h.add(300, at(1100));
+        // two intervals without progress
+        h.add(600, at(4000));
+        assert_eq!(h.samples, [300, 300, 0, 0]);
+        assert!(h.advance(at(5000)));
+        assert!(!h.advance(at(5500)));
+        assert_eq!(h.samples, [300, 300, 0, 0, 600]);
+        // idle
+        h.advance(at(7500));
+        assert_eq!(h.samples, [0, 0, 600, 0, 0]);
+        h.add(70, at(60000));
+        h.advance(at(61000));
+        assert_eq!(h.samples, [0, 0, 0, 0, 70]);
+        assert_eq!(h.max(), 70);
+        assert!(h.newest_ms.is_some());
+        let mut h = RateHistory::new(Duration::from_secs(1), Duration::from_secs(3));
+        h.add(10, at(0));
+        h.add(20, at(1000));
+        h.advance(at(2000));
+        assert_eq!(h.points(100, 10), "50.0,5.0 100.0,0.0");
+    }
+}
@@ -0,0 +1,254 @@
+//! Terminal client. Watches the daemon's state over the control socket and
+//! sends it the same commands a user would type into the socket.
+use crate::*;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::{
+    layout::{Constraint, Layout},
+    style::{Modifier, Style, Stylize},
+    text::Line,
+    widgets::{Block, Gauge, List, ListItem, ListState, Paragraph, Sparkline},
+    Frame,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    select,
+};
+use tokio_stream::StreamExt;
+
+enum Mode {
+    Normal,
+    /// Typing a URL to add
+    Input(String),
+}
+
+enum Action {
+    Quit,
+    Send(String),
+}
+
+struct App {
+    state: Tracker,
+    queue_state: ListState,
+    mode: Mode,
+    /// Error reported by the daemon, shown until the next key press
+    message: Option<String>,
+}
+
+pub async fn run(socket_path: PathBuf) -> Anything<()> {
+    let stream = UnixStream::connect(&socket_path).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    writer.write_all(b"watch\n").await?;
+    let mut app = App {
+        state: Tracker::new(),
+        queue_state: ListState::default(),
+        mode: Mode::Normal,
+        message: None,
+    };
+    let mut terminal = ratatui::init();
+    let mut events = EventStream::new();
+    let result: Anything<()> = loop {
+        if let Err(e) = terminal.draw(|f| app.draw(f)) {
+            break Err(e.into());
+        }
+        select! {
+            line = lines.next_line() => {
+                match line {
+                    Ok(Some(line)) => match serde_json::from_str(&line) {
+                        Ok(state) => app.set_state(state),
+                        Err(_) if line.starts_with("error:") => app.message = Some(line),
+                        Err(e) => break Err(e.into()),
+                    },
+                    Ok(None) => break Err("daemon closed the connection".into()),
+                    Err(e) => break Err(e.into()),
+                }
+            },
+            event = events.next() => {
+                let Some(Ok(Event::Key(key))) = event else { continue };
+                match app.handle_key(key) {
+                    Some(Action::Quit) => break Ok(()),
+                    Some(Action::Send(mut cmd)) => {
+                        cmd.push('\n');
+                        if let Err(e) = writer.write_all(cmd.as_bytes()).await {
+                            break Err(e.into());
+                        }
+                    },
+                    None => {},
+                }
+            },
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+impl App {
+    fn set_state(&mut self, state: Tracker) {
+        self.state = state;
+        let len = self.state.queue.len();
+        match self.queue_state.selected() {
+            _ if len == 0 => self.queue_state.select(None),
+            Some(i) if i >= len => self.queue_state.select(Some(len - 1)),
+            None => self.queue_state.select(Some(0)),
+            _ => {},
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Option<Action> {
+        if key.kind != KeyEventKind::Press {
+            return None;
+        }
+        self.message = None;
+        if let Mode::Input(url) = &mut self.mode {
+            match key.code {
+                KeyCode::Esc => self.mode = Mode::Normal,
+                KeyCode::Backspace => {
+                    url.pop();
+                },
+                KeyCode::Enter => {
+                    let url = url.trim().to_string();
+                    self.mode = Mode::Normal;
+                    if !url.is_empty() {
+                        return Some(Action::Send(format!("add {url}")));
+                    }
+                },
+                KeyCode::Char(c) => url.push(c),
+                _ => {},
+            }
+            return None;
+        }
+        let selected = self.queue_state.selected();
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Some(Action::Quit),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Some(Action::Quit)
+            },
+            KeyCode::Char('a') => self.mode = Mode::Input(String::new()),
+            KeyCode::Char('j') | KeyCode::Down => self.queue_state.select_next(),
+            KeyCode::Char('k') | KeyCode::Up => self.queue_state.select_previous(),
+            KeyCode::Char('J') => {
+                let i = selected?;
+                if i + 1 < self.state.queue.len() {
+                    self.queue_state.select(Some(i + 1));
+                }
+                return Some(Action::Send(format!("down {i}")));
+            },
+            KeyCode::Char('K') => {
+                let i = selected?;
+                self.queue_state.select(Some(i.saturating_sub(1)));
+                return Some(Action::Send(format!("up {i}")));
+            },
+            KeyCode::Char('d') | KeyCode::Delete => {
+                return Some(Action::Send(format!("delete {}", selected?)))
+            },
+            KeyCode::Char('p') => return Some(Action::Send("pause".into())),
+            KeyCode::Char('r') => return Some(Action::Send("resume".into())),
+            KeyCode::Char('c') => return Some(Action::Send("cancel".into())),
+            _ => {},
+        }
+        None
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let history_height = self.state.history.len().clamp(1, 8) as u16 + 2;
+        let [header, gauge, chart, queue, history, footer] = Layout::vertical([
+            Constraint::Length(2),
+            Constraint::Length(3),
+            Constraint::Length(5),
+            Constraint::Min(3),
+            Constraint::Length(history_height),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let state = &self.state;
+        let title = state.title.as_deref().unwrap_or("-");
+        frame.render_widget(
+            Paragraph::new(vec![
+                Line::from(state.state.to_string()).bold(),
+                Line::from(title),
+            ]),
+            header,
+        );
+
+        let mut label = format!("{:.1}%", state.progress.unwrap_or(0.0) * 100.0);
+        if let Some(total) = state.total_bytes {
+            label += &format!(
+                " | {} / {}",
+                humanize_bytes(state.downloaded_bytes()),
+                humanize_bytes(total)
+            );
+        }
+        if let Some(rate) = &state.rate_h {
+            label += &format!(" | {rate}");
+        }
+        if let Some(eta) = state.eta {
+            label += &format!(" | ETA {}", humanize_duration(eta));
+        }
+        if let Some(eta) = state.queue_eta {
+            label += &format!(" | Queue ETA {}", humanize_duration(eta));
+        }
+        frame.render_widget(
+            Gauge::default()
+                .block(Block::bordered().title("Progress"))
+                .ratio(state.progress.unwrap_or(0.0))
+                .label(label),
+            gauge,
+        );
+
+        // as many of the newest samples as fit
+        let samples = &state.rate_history.samples;
+        let width = chart.width.saturating_sub(2) as usize;
+        let data: Vec<u64> = samples.iter().skip(samples.len().saturating_sub(width)).copied().collect();
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title(format!("Rate (peak {})", state.rate_history.peak())))
+                .data(&data),
+            chart,
+        );
+
+        let items: Vec<ListItem> = state.queue.iter().map(|job| ListItem::new(job.url.as_str())).collect();
+        let list = List::new(items)
+            .block(Block::bordered().title(format!("Queue ({})", state.queue.len())))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, queue, &mut self.queue_state);
+
+        let items: Vec<ListItem> = self
+            .state
+            .history
+            .iter()
+            .map(|entry| {
+                let name = entry.title.as_deref().unwrap_or(&entry.job.url);
+                ListItem::new(format!("{name} - {}", entry.reason))
+            })
+            .collect();
+        frame.render_widget(
+            List::new(items).block(Block::bordered().title("Recent")),
+            history,
+        );
+
+        let footer_line = match &self.mode {
+            Mode::Normal if self.message.is_some() => {
+                Line::from(self.message.as_deref().unwrap_or_default()).red()
+            },
+            Mode::Normal => Line::from(
+                "a add | j/k select | J/K move | d delete | p pause | r resume | c cancel | q quit",
+            )
+            .dim(),
+            Mode::Input(url) => Line::from(format!("URL: {url}_")),
+        };
+        frame.render_widget(Paragraph::new(footer_line), footer);
+    }
+}
+
+fn humanize_duration(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{h}h{m:02}m{s:02}s")
+    } else if m > 0 {
+        format!("{m}m{s:02}s")
+    } else {
+        format!("{s}s")
+    }
+}
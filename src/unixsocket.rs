@@ -1,48 +1,244 @@
-use crate::DownloaderCommand;
+use crate::{Anything, DownloaderCommand, tracker::Tracker, config::ReloadReply};
+use crate::commands::CommandRequest;
+use crate::history::{self, History};
 use std::path::Path;
 use std::str::FromStr;
 use tokio::{
-    io::{AsyncBufReadExt, BufReader},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
     net::{UnixListener, UnixStream},
-    sync::mpsc::UnboundedSender,
+    select,
+    sync::{mpsc::UnboundedSender, oneshot, watch},
 };
 mod parser;
+mod access;
+pub use access::{resolve_group, current_uid, Access, AccessPolicy};
+use tracing::{debug, warn};
+
+/// Requests that are answered by the socket server itself, without
+/// involving the downloader
+#[derive(PartialEq, Eq, Debug)]
+pub enum Request {
+    /// Reply with a single JSON line describing the current state
+    Status,
+    /// Reply with a JSON line every time the state changes
+    Watch,
+    /// Re-read the configuration file. Replies with the changes as JSON.
+    Reload,
+    /// Reply with a JSON line listing the matching downloads
+    History(history::Query),
+    /// Reply with a JSON line of download statistics
+    Stats,
+}
+
+impl Request {
+    fn is_query(&self) -> bool {
+        matches!(self, Request::Status | Request::Watch | Request::History(_) | Request::Stats)
+    }
+}
+
+/// Where the socket server sends the requests it does not answer itself
+#[derive(Clone)]
+pub struct Handlers {
+    pub command: UnboundedSender<CommandRequest>,
+    pub reload: UnboundedSender<oneshot::Sender<ReloadReply>>,
+    /// Unset when the history is disabled
+    pub history: Option<History>,
+}
+
+/// Binds a unix socket with the configured group and mode. An existing
+/// socket file is replaced, anything else at the path is an error. With a
+/// group or mode, the socket is created for the owner only and opened up once
+/// both are set, so nobody connects in between. The socket file is removed
+/// again if that fails.
+pub async fn bind(path: &Path, gid: Option<u32>, mode: Option<u32>) -> Anything<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(meta) if meta.file_type().is_socket() => tokio::fs::remove_file(path).await?,
+        Ok(_) => return Err(format!("Not a socket, refusing to replace it: {}", path.display()).into()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let restrict = gid.is_some() || mode.is_some();
+    // the umask is per process, sockets are only bound at startup
+    // SAFETY: umask cannot fail
+    let umask = restrict.then(|| unsafe { libc::umask(0o177) });
+    let listener = UnixListener::bind(path);
+    if let Some(umask) = umask {
+        unsafe { libc::umask(umask) };
+    }
+    let listener = listener.map_err(|e| format!("Could not listen on {}: {e}", path.display()))?;
+    let opened = async {
+        if let Some(gid) = gid {
+            std::os::unix::fs::chown(path, None, Some(gid))?;
+        }
+        if let Some(umask) = umask {
+            // what the umask would have allowed, unless a mode is configured
+            let mode = mode.unwrap_or(0o777 & !umask);
+            tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    if let Err(e) = opened.await {
+        _ = tokio::fs::remove_file(path).await;
+        return Err(format!("Could not set the owner or mode of {}: {e}", path.display()).into());
+    }
+    Ok(listener)
+}
 
 pub async fn server(
     socket: UnixListener,
-    tx_command: UnboundedSender<DownloaderCommand>,
+    handlers: Handlers,
+    state_rx: watch::Receiver<Tracker>,
+    policy: AccessPolicy,
+    mut shutdown_rx: watch::Receiver<bool>,
 ) -> Result<(), std::io::Error> {
     loop {
-        let (stream, _) = socket.accept().await?;
-        tokio::spawn(handle_stream(stream, tx_command.clone()));
+        let (stream, _) = select! {
+            accepted = socket.accept() => accepted?,
+            _ = crate::shutdown::requested(&mut shutdown_rx) => break,
+        };
+        let cred = match stream.peer_cred() {
+            Ok(cred) => cred,
+            Err(e) => {
+                warn!("Could not read peer credentials: {e}");
+                continue;
+            }
+        };
+        let access = policy.check_peer(&cred);
+        if access == Access::Denied {
+            warn!(
+                "Rejected connection from pid {:?} uid {} gid {}",
+                cred.pid(),
+                cred.uid(),
+                cred.gid()
+            );
+            continue;
+        }
+        debug!("Accepted connection from pid {:?} uid {} ({access:?})", cred.pid(), cred.uid());
+        tokio::spawn(handle_stream(stream, handlers.clone(), state_rx.clone(), access));
     }
     Ok(())
 }
 
 pub async fn handle_stream(
     stream: UnixStream,
-    tx_command: UnboundedSender<DownloaderCommand>,
+    handlers: Handlers,
+    mut state_rx: watch::Receiver<Tracker>,
+    access: Access,
 ) -> Result<(), std::io::Error> {
-    let mut client = BufReader::new(stream).lines();
+    let (reader, mut writer) = stream.into_split();
+    let mut client = BufReader::new(reader).lines();
+    let mut watching = false;
     loop {
-        // TODO: implement timeout here?
-        let line = client.next_line().await?;
-        if let Some(line) = line {
-            // TODO: decode the command
-            let msg = DownloaderCommand::from_str(&line);
-            // ignore lines that cannot be parsed
-            if let Ok(cmd) = msg {
-                if tx_command.send(cmd).is_err() {
+        select! {
+            // TODO: implement timeout here?
+            line = client.next_line() => {
+                let Some(line) = line? else { break };
+                if let Ok(request) = Request::from_str(&line) {
+                    if !request.is_query() && access != Access::Full {
+                        writer.write_all(b"error: read-only access\n").await?;
+                        continue;
+                    }
+                    match request {
+                        Request::Reload => {
+                            let (reply_tx, reply_rx) = oneshot::channel();
+                            if handlers.reload.send(reply_tx).is_err() {
+                                break;
+                            }
+                            let reply = match reply_rx.await {
+                                Ok(Ok(changes)) => serde_json::json!({ "reloaded": changes }).to_string(),
+                                Ok(Err(e)) => format!("error: {e}"),
+                                Err(_) => "error: reload failed".into(),
+                            };
+                            writer.write_all(format!("{reply}\n").as_bytes()).await?;
+                        }
+                        Request::History(query) => {
+                            let reply = match &handlers.history {
+                                Some(history) => match history.search(query).await {
+                                    Ok(records) => serde_json::to_string(&records)?,
+                                    Err(e) => format!("error: {e}"),
+                                },
+                                None => "error: history is disabled".into(),
+                            };
+                            writer.write_all(format!("{reply}\n").as_bytes()).await?;
+                        }
+                        Request::Stats => {
+                            let reply = match &handlers.history {
+                                Some(history) => match history.stats().await {
+                                    Ok(stats) => serde_json::to_string(&stats)?,
+                                    Err(e) => format!("error: {e}"),
+                                },
+                                None => "error: history is disabled".into(),
+                            };
+                            writer.write_all(format!("{reply}\n").as_bytes()).await?;
+                        }
+                        request => {
+                            if request == Request::Watch {
+                                watching = true;
+                            }
+                            let state = state_rx.borrow_and_update().clone();
+                            write_state(&mut writer, &state).await?;
+                        }
+                    }
+                    continue;
+                }
+                // ignore lines that cannot be parsed
+                if let Ok(cmd) = DownloaderCommand::from_str(&line) {
+                    if access != Access::Full {
+                        writer.write_all(b"error: read-only access\n").await?;
+                        continue;
+                    }
+                    let (request, reply_rx) = CommandRequest::new(cmd);
+                    if handlers.command.send(request).is_err() {
+                        break;
+                    }
+                    // only failures are answered, successes show up in the state
+                    if let Ok(Err(e)) = reply_rx.await {
+                        writer.write_all(format!("error: {e}\n").as_bytes()).await?;
+                    }
+                }
+            },
+            changed = state_rx.changed(), if watching => {
+                if changed.is_err() {
                     break;
                 }
-            }
-        } else {
-            break;
+                let state = state_rx.borrow_and_update().clone();
+                write_state(&mut writer, &state).await?;
+            },
         }
     }
     Ok(())
 }
 
+async fn write_state(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    state: &Tracker,
+) -> Result<(), std::io::Error> {
+    let mut line = serde_json::to_string(state)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await
+}
+
 pub async fn prep_socket_path(path: impl AsRef<Path>) {
     _ = tokio::fs::remove_file(path).await;
 }
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn check_bind() {
+        let path = std::env::temp_dir().join(format!("downd-bind-{}", std::process::id()));
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(bind(&path, None, None).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+        drop(bind(&path, None, None).await.unwrap());
+        // a stale socket is replaced
+        let _listener = bind(&path, None, Some(0o600)).await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
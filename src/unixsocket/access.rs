@@ -0,0 +1,152 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use tokio::net::unix::UCred;
+
+/// What a connected peer is allowed to do
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Access {
+    /// Queries and commands
+    Full,
+    /// Queries only
+    ReadOnly,
+    Denied,
+}
+
+/// Decides access from the peer credentials reported by SO_PEERCRED. The
+/// daemon's own user and root always get full access. Groups are the peer
+/// user's primary group and the supplementary groups from the group database.
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    pub own_uid: u32,
+    pub allow_uids: Vec<u32>,
+    pub allow_gids: Vec<u32>,
+    pub readonly_uids: Vec<u32>,
+    pub readonly_gids: Vec<u32>,
+}
+
+impl AccessPolicy {
+    pub fn check(&self, uid: u32, gids: &[u32]) -> Access {
+        let any = |allowed: &[u32]| gids.iter().any(|gid| allowed.contains(gid));
+        if uid == 0
+            || uid == self.own_uid
+            || self.allow_uids.contains(&uid)
+            || any(&self.allow_gids)
+        {
+            Access::Full
+        } else if self.readonly_uids.contains(&uid) || any(&self.readonly_gids) {
+            Access::ReadOnly
+        } else {
+            Access::Denied
+        }
+    }
+    pub fn check_peer(&self, cred: &UCred) -> Access {
+        let (uid, gid) = (cred.uid(), cred.gid());
+        // the group database is only asked if groups are configured
+        if self.allow_gids.is_empty() && self.readonly_gids.is_empty() {
+            return self.check(uid, &[gid]);
+        }
+        let gids = user_groups(uid, gid).unwrap_or_else(|e| {
+            tracing::warn!("Could not read the groups of uid {uid}: {e}");
+            vec![gid]
+        });
+        self.check(uid, &gids)
+    }
+}
+
+/// Calls a reentrant libc lookup with a growing buffer, until the result fits
+fn with_buffer<T>(mut lookup: impl FnMut(&mut [libc::c_char]) -> io::Result<T>) -> io::Result<T> {
+    let mut buf = vec![0; 1024];
+    loop {
+        match lookup(&mut buf) {
+            Err(e) if e.raw_os_error() == Some(libc::ERANGE) && buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            result => return result,
+        }
+    }
+}
+
+/// The primary and supplementary groups of a user
+fn user_groups(uid: u32, gid: u32) -> io::Result<Vec<u32>> {
+    let name = with_buffer(|buf| {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        // SAFETY: all pointers are valid for the call, the strings in pwd
+        // point into buf, which outlives their use
+        let err = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+        match (err, found.is_null()) {
+            (0, true) => Err(io::Error::new(io::ErrorKind::NotFound, "no such user")),
+            (0, false) => Ok(unsafe { CStr::from_ptr(pwd.pw_name) }.to_owned()),
+            (err, _) => Err(io::Error::from_raw_os_error(err)),
+        }
+    })?;
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        // SAFETY: groups has room for count entries
+        let n = unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if n >= 0 {
+            groups.truncate(n as usize);
+            return Ok(groups);
+        }
+        // count is now the number needed
+        groups.resize((count as usize).max(groups.len() * 2), 0);
+    }
+}
+
+pub fn current_uid() -> u32 {
+    // SAFETY: getuid has no preconditions and cannot fail
+    unsafe { libc::getuid() }
+}
+
+/// Resolves a group given either as a numeric GID or as a group name
+pub fn resolve_group(group: &str) -> Option<u32> {
+    if let Ok(gid) = group.parse() {
+        return Some(gid);
+    }
+    let name = CString::new(group).ok()?;
+    let lookup = with_buffer(|buf| {
+        let mut grp: libc::group = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        // SAFETY: all pointers are valid for the call, only the gid is read
+        let err = unsafe { libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut found) };
+        match err {
+            0 => Ok((!found.is_null()).then_some(grp.gr_gid)),
+            err => Err(io::Error::from_raw_os_error(err)),
+        }
+    });
+    lookup.ok().flatten()
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+    #[test]
+    fn check_policy() {
+        let policy = AccessPolicy {
+            own_uid: 1000,
+            allow_uids: vec![1001],
+            allow_gids: vec![100],
+            readonly_uids: vec![1002],
+            readonly_gids: vec![200],
+        };
+        assert_eq!(policy.check(0, &[0]), Access::Full);
+        assert_eq!(policy.check(1000, &[1000]), Access::Full);
+        assert_eq!(policy.check(1001, &[1001]), Access::Full);
+        assert_eq!(policy.check(1005, &[100]), Access::Full);
+        assert_eq!(policy.check(1002, &[1002]), Access::ReadOnly);
+        assert_eq!(policy.check(1005, &[200]), Access::ReadOnly);
+        assert_eq!(policy.check(1005, &[1005]), Access::Denied);
+        // supplementary groups count too, the better one wins
+        assert_eq!(policy.check(1005, &[1005, 200]), Access::ReadOnly);
+        assert_eq!(policy.check(1005, &[200, 100]), Access::Full);
+    }
+    #[test]
+    fn check_numeric_group() {
+        assert_eq!(resolve_group("1234"), Some(1234));
+    }
+    #[test]
+    fn check_group_database() {
+        assert_eq!(resolve_group("root"), Some(0));
+        assert_eq!(resolve_group("no-such-group-downd"), None);
+        assert!(user_groups(0, 0).unwrap().contains(&0));
+    }
+}
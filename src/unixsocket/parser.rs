@@ -1,10 +1,12 @@
+use super::Request;
+use crate::history::Query;
 use crate::DownloaderCommand;
 use nom::{
     branch::alt,
     bytes::complete::tag_no_case,
-    character::complete::{digit1, not_line_ending, space1},
-    combinator::{map, map_res},
-    sequence::separated_pair,
+    character::complete::{digit1, multispace1, not_line_ending, space1},
+    combinator::{eof, map, map_res, opt, peek},
+    sequence::{preceded, separated_pair, terminated},
     Finish, IResult,
 };
 use std::str::FromStr;
@@ -13,28 +15,44 @@ fn parse_int<T: FromStr>(input: &str) -> IResult<&str, T> {
     map_res(digit1, |x| T::from_str(x))(input)
 }
 
+/// A keyword that ends the input or is followed by whitespace, so `statusfoo`
+/// is not `status`
+fn keyword<'a>(word: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    terminated(tag_no_case(word), peek(alt((multispace1, eof))))
+}
+
 fn add_url_cmd(input: &str) -> IResult<&str, DownloaderCommand> {
     let p = separated_pair(tag_no_case("add"), space1, not_line_ending);
     map(p, |(_, url): (_, &str)| {
-        DownloaderCommand::AddUrl(url.to_string())
+        DownloaderCommand::AddUrl(url.trim_end().to_string())
     })(input)
 }
 
 fn pause_cmd(input: &str) -> IResult<&str, DownloaderCommand> {
-    let p = tag_no_case("pause");
+    let p = keyword("pause");
     map(p, |_| DownloaderCommand::Pause)(input)
 }
 
 fn cancel_cmd(input: &str) -> IResult<&str, DownloaderCommand> {
-    let p = tag_no_case("cancel");
+    let p = keyword("cancel");
     map(p, |_| DownloaderCommand::Cancel)(input)
 }
 
 fn resume_cmd(input: &str) -> IResult<&str, DownloaderCommand> {
-    let p = tag_no_case("resume");
+    let p = keyword("resume");
     map(p, |_| DownloaderCommand::Resume)(input)
 }
 
+fn retry_cmd(input: &str) -> IResult<&str, DownloaderCommand> {
+    let p = keyword("retry");
+    map(p, |_| DownloaderCommand::Retry)(input)
+}
+
+fn skip_cmd(input: &str) -> IResult<&str, DownloaderCommand> {
+    let p = keyword("skip");
+    map(p, |_| DownloaderCommand::Skip)(input)
+}
+
 fn movedown_cmd(input: &str) -> IResult<&str, DownloaderCommand> {
     let p = separated_pair(tag_no_case("down"), space1, parse_int);
     map(p, |(_, index)| DownloaderCommand::MoveDown(index))(input)
@@ -59,6 +77,8 @@ impl FromStr for DownloaderCommand {
             pause_cmd,
             cancel_cmd,
             resume_cmd,
+            retry_cmd,
+            skip_cmd,
             movedown_cmd,
             moveup_cmd,
             delete_cmd,
@@ -71,6 +91,65 @@ impl FromStr for DownloaderCommand {
     }
 }
 
+fn status_query(input: &str) -> IResult<&str, Request> {
+    let p = keyword("status");
+    map(p, |_| Request::Status)(input)
+}
+
+fn watch_query(input: &str) -> IResult<&str, Request> {
+    let p = keyword("watch");
+    map(p, |_| Request::Watch)(input)
+}
+
+fn stats_query(input: &str) -> IResult<&str, Request> {
+    let p = keyword("stats");
+    map(p, |_| Request::Stats)(input)
+}
+
+fn reload_request(input: &str) -> IResult<&str, Request> {
+    let p = keyword("reload");
+    map(p, |_| Request::Reload)(input)
+}
+
+/// `key=value` words are filters, everything else is text to search for
+fn history_filters(input: &str) -> Result<Query, String> {
+    let mut query = Query::default();
+    let mut text = vec![];
+    for word in input.split_whitespace() {
+        match word.split_once('=') {
+            Some(("status", v)) => query.status = Some(v.parse()?),
+            Some(("since", v)) => query.since = Some(v.into()),
+            Some(("until", v)) => query.until = Some(v.into()),
+            Some(("subdir", v)) => query.subdir = Some(v.into()),
+            Some(("limit", v)) => query.limit = Some(v.parse().map_err(|_| format!("bad limit: {v}"))?),
+            Some(("offset", v)) => query.offset = Some(v.parse().map_err(|_| format!("bad offset: {v}"))?),
+            _ => text.push(word),
+        }
+    }
+    if !text.is_empty() {
+        query.text = Some(text.join(" "));
+    }
+    Ok(query)
+}
+
+fn history_query(input: &str) -> IResult<&str, Request> {
+    let p = preceded(keyword("history"), opt(preceded(space1, not_line_ending)));
+    map(map_res(p, |filters| history_filters(filters.unwrap_or(""))), Request::History)(input)
+}
+
+impl FromStr for Request {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut requests = alt((status_query, stats_query, watch_query, reload_request, history_query));
+        if let Ok((_, request)) = requests(s).finish() {
+            Ok(request)
+        } else {
+            Err(())
+        }
+    }
+}
+
 #[cfg(test)]
 mod checks {
     use super::*;
@@ -99,6 +178,11 @@ mod checks {
         assert_eq!(input.parse(), Ok(cmd));
     }
     #[test]
+    fn check_retry_skip() {
+        assert_eq!("retry\n".parse(), Ok(DownloaderCommand::Retry));
+        assert_eq!("Skip".parse(), Ok(DownloaderCommand::Skip));
+    }
+    #[test]
     fn check_uint_parse() {
         let input = "1234";
         assert_eq!(parse_int::<usize>(input), Ok(("", 1234)));
@@ -124,4 +208,32 @@ mod checks {
         // assert_eq!(delete_cmd(input), Ok(("\n", cmd)));
         assert_eq!(input.parse(), Ok(cmd));
     }
+    #[test]
+    fn check_requests() {
+        assert_eq!("status\n".parse(), Ok(Request::Status));
+        assert_eq!("WATCH".parse(), Ok(Request::Watch));
+        assert_eq!("reload".parse(), Ok(Request::Reload));
+        assert_eq!("add www.google.com".parse::<Request>(), Err(()));
+        assert_eq!("statusfoo".parse::<Request>(), Err(()));
+        assert_eq!("status all".parse(), Ok(Request::Status));
+        assert_eq!("pauses".parse::<DownloaderCommand>(), Err(()));
+    }
+    #[test]
+    fn check_history() {
+        assert_eq!("history\n".parse(), Ok(Request::History(Query::default())));
+        let query = Query {
+            text: Some("cat videos".into()),
+            status: Some(crate::history::Status::Failed),
+            since: Some("2024-05-01".into()),
+            subdir: Some("talks".into()),
+            limit: Some(10),
+            ..Query::default()
+        };
+        let input = "History status=failed cat since=2024-05-01 videos subdir=talks limit=10\n";
+        assert_eq!(input.parse(), Ok(Request::History(query)));
+        assert_eq!("history status=gone".parse::<Request>(), Err(()));
+        assert_eq!("stats\n".parse(), Ok(Request::Stats));
+        assert_eq!("history limit=many".parse::<Request>(), Err(()));
+        assert_eq!("historyx".parse::<Request>(), Err(()));
+    }
 }
@@ -4,71 +4,181 @@ use std::{net::SocketAddr, convert::Infallible, ops::DerefMut};
 use askama::Template;
 use tokio::{
     select,
-    time::Duration,
+    time::{Duration, Instant},
     sync::{broadcast, mpsc},
     stream,
 };
-use warp::{http::StatusCode, Filter, sse::Event};
+use warp::{http::StatusCode, Filter, Reply, sse::Event};
 use tokio_stream::{
     Stream,
     StreamExt,
-    wrappers::BroadcastStream,     
 };
 use std::sync::{Arc, Mutex};
 
-use crate::{humanize_bytes, DownloaderMsg, Config};
-use crate::rollingrate::RollingRate;
+use crate::{humanize_bytes, DownloaderMsg, Config, Settings};
+use crate::commands::CommandRequest;
+use crate::tracker::Tracker;
+use crate::history::History;
+
+mod api;
+mod auth;
+mod dates;
+mod events;
+mod feeds;
+mod files;
+mod frames;
+mod health;
+mod history;
+pub mod listen;
+mod metrics;
+mod send;
+mod stats;
+mod tls;
+use frames::{Dirty, Frame, Part};
+pub use health::Probes;
 
 #[derive(Template)]
 #[template(path = "root.html")]
-struct Root { }
+struct Root {
+    /// Empty unless logged in through the form
+    csrf: String,
+}
 
 #[derive(Clone)]
-struct UpdateChan<T>(Arc<Mutex<broadcast::Sender<T>>>);
-impl<T: Clone> UpdateChan<T> {
-    fn new() -> Self {
-        let (ch, _) = broadcast::channel(32);
+struct UpdateChan(Arc<Mutex<broadcast::Sender<Frame>>>);
+impl UpdateChan {
+    fn new(capacity: usize) -> Self {
+        let (ch, _) = broadcast::channel(capacity);
         Self(Arc::new(Mutex::new(ch)))
     }
-    fn subscribe(&self) -> broadcast::Receiver<T> {
+    fn subscribe(&self) -> broadcast::Receiver<Frame> {
         self.0.lock().unwrap().subscribe()
     }
-    fn send(&self, msg: T) -> Result<usize, tokio::sync::broadcast::error::SendError<T>> {
-        self.0.lock().unwrap().send(msg)
+    fn receiver_count(&self) -> usize {
+        self.0.lock().unwrap().receiver_count()
+    }
+    fn send(&self, frame: Frame) -> Result<usize, tokio::sync::broadcast::error::SendError<Frame>> {
+        self.0.lock().unwrap().send(frame)
     }
 }
 
-pub async fn server(update_rx: broadcast::Receiver<DownloaderMsg>,
-                    port: u16,
+/// Subscribes to the downloader's updates before its first await, so it
+/// sees everything the downloader sends once both run
+pub async fn server(probes: Probes,
+                    listeners: Vec<listen::Listener>,
+                    shutdown_rx: tokio::sync::watch::Receiver<bool>,
+                    settings_rx: tokio::sync::watch::Receiver<Settings>,
+                    cmd_tx: mpsc::UnboundedSender<CommandRequest>,
+                    state_rx: tokio::sync::watch::Receiver<Tracker>,
+                    history: Option<History>,
                     ) {
     // let (update_chan, _) = broadcast::channel::<DownloaderMsg>(32);
     // let update_chan = Arc::new(Mutex::new(update_chan));
-    let update_chan = UpdateChan::new();
+    let update_rx = probes.update_tx.subscribe();
+    // the web server's own settings only change on restart
+    let settings = settings_rx.borrow().clone();
+    let update_chan = UpdateChan::new(settings.web.sse_buffer);
     info!("Starting web server");
-    let (kick_tx, kick_rx) = mpsc::channel(1);
-    tokio::task::spawn(statemonitor(update_rx, update_chan.clone(), kick_rx));
+    let tls = match (&settings.web.tls_cert, &settings.web.tls_key) {
+        (Some(cert), Some(key)) => match tls::acceptor(cert.clone(), key.clone(), shutdown_rx.clone()) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                error!("Web server not started: {e}");
+                return;
+            }
+        },
+        _ => None,
+    };
+    let auth = auth::Auth::new(&settings.auth, tls.is_some());
+    if !auth.enabled() && listeners.iter().any(listen::Listener::is_public) {
+        warn!("The web server is reachable from other hosts, but no [auth] tokens or users are configured");
+    }
+    let event_log = events::EventLog::new(state_rx.borrow().clone(), settings.web.sse_buffer);
+    tokio::task::spawn(events::publisher(state_rx.clone(), event_log.clone()));
+    let metrics = metrics::Metrics::default();
+    tokio::task::spawn(metrics::collector(update_rx, metrics.clone()));
+    tokio::task::spawn(statemonitor(
+        state_rx.clone(),
+        update_chan.clone(),
+        shutdown_rx.clone(),
+        settings.web.frame_interval(),
+    ));
     let root_route = warp::path!("root")
         .and(warp::get())
+        .and(auth.allow())
         // and_then requires a fn that returns a TryFuture, whose
         // error type is warp::Rejection
         .and_then(test_root);
+    let sse_shutdown = shutdown_rx.clone();
+    let sse_state = state_rx.clone();
+    let page_clients = update_chan.clone();
     let sse_route = warp::path("sse")
         .and(warp::get())
-        .map(move || update_chan.subscribe())
+        .and(auth.allow())
+        .map(move |_| update_chan.clone())
         .map(move |a| {
-            warp::sse::reply(warp::sse::keep_alive().stream(sse_test(a)))
+            // end the stream on shutdown, or the server would wait for it
+            let mut shutdown_rx = sse_shutdown.clone();
+            let stream = futures_util::StreamExt::take_until(sse_test(a, sse_state.clone()), async move {
+                crate::shutdown::requested(&mut shutdown_rx).await
+            });
+            warp::sse::reply(warp::sse::keep_alive().stream(stream))
         });
-    let sse_kick = warp::path("sse")
-        .and(warp::post())
-        .map(move || kick_tx.clone())
-        .and_then(kick)
-        ;
-    let routes = root_route
-        .or(sse_kick)
-        .or(sse_route)
-        ;
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    warp::serve(routes).run(addr).await;
+    let metrics_route = metrics::route(metrics, state_rx.clone(), auth.clone());
+    let api = api::Api::new(cmd_tx, state_rx, history, settings_rx);
+    let health_routes = health::routes(api.clone(), &probes, page_clients, event_log.clone(), auth.clone());
+    let send_routes = send::routes(
+        api.clone(),
+        auth.clone(),
+        settings.profiles.clone(),
+    );
+    let history_route = history::route(api.clone(), auth.clone());
+    let stats_route = stats::route(api.clone(), auth.clone());
+    let files_route = match (settings.web.serve_files, &settings.downloader.output_dir) {
+        (true, Some(dir)) => match settings.web.base_url() {
+            Some(base) => files::routes(dir.clone(), auth.clone())
+                .or(feeds::routes(api.clone(), dir.clone(), auth.clone(), base.to_string()))
+                .unify()
+                .boxed(),
+            None => {
+                info!("Feeds need web.base_url, they are not served");
+                files::routes(dir.clone(), auth.clone()).boxed()
+            }
+        },
+        (serve, _) => {
+            if serve {
+                warn!("web.serve_files needs downloader.output_dir, files are not served");
+            }
+            warp::any().and_then(|| async { Err(warp::reject::not_found()) }).boxed()
+        }
+    };
+    let pages = root_route
+        .map(Reply::into_response)
+        .or(sse_route.map(Reply::into_response))
+        .unify()
+        .or(send_routes)
+        .unify()
+        .or(history_route)
+        .unify()
+        .or(stats_route)
+        .unify()
+        .or(files_route)
+        .unify()
+        .recover(auth::login_redirect)
+        .unify();
+    let routes = auth::routes(auth.clone())
+        .or(api::routes(api, event_log, shutdown_rx.clone(), auth))
+        .or(metrics_route)
+        .or(health_routes)
+        .or(pages);
+    let incoming = listen::incoming(listeners, tls, shutdown_rx.clone());
+    let mut shutdown_rx = shutdown_rx;
+    warp::serve(routes)
+        .serve_incoming_with_graceful_shutdown(incoming, async move {
+            crate::shutdown::requested(&mut shutdown_rx).await
+        })
+        .await;
+    info!("Web server stopped");
     // .with(warp::cors().allow_any_origin())
 }
 
@@ -76,28 +186,52 @@ fn humanize_rate(r: Option<u64>) -> Option<String> {
     r.map(|r| format!("{}/s", humanize_bytes(r)))
 }
 
-/// keeps the state tracker in a dedicated task and manages the update 
-/// broadcast channel
-async fn statemonitor(mut update_rx: broadcast::Receiver<DownloaderMsg>, chan: UpdateChan<String>, mut kick_chan: mpsc::Receiver<()>) {
+/// manages the update broadcast channel. Changes of the tracker are
+/// collected and rendered at most once per frame interval, only the parts
+/// that changed and only if anyone listens. New clients start from a
+/// snapshot of their own.
+async fn statemonitor(
+    mut state_rx: tokio::sync::watch::Receiver<Tracker>,
+    chan: UpdateChan,
+    mut shutdown_rx: tokio::sync::watch::Receiver<bool>,
+    frame_interval: Duration,
+) {
     debug!("statemonitor started");
-    let mut update = crate::tracker::Tracker::new();
+    let mut last = state_rx.borrow_and_update().clone();
+    let mut last_frame = Instant::now() - frame_interval;
+    let next_frame = tokio::time::sleep(Duration::ZERO);
+    tokio::pin!(next_frame);
+    let mut armed = false;
     loop {
         select! {
-            Ok(msg) = update_rx.recv() => {
-                update.update(msg);
+            changed = state_rx.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                if !armed {
+                    armed = true;
+                    next_frame.as_mut().reset(last_frame + frame_interval);
+                }
             },
-            _ = kick_chan.recv() => {
+            _ = &mut next_frame, if armed => {
+                armed = false;
+                let current = state_rx.borrow_and_update().clone();
+                let parts = Dirty::between(&last, &current).take();
+                if chan.receiver_count() > 0 && !parts.is_empty() {
+                    for part in parts {
+                        match part.render(&current) {
+                            Ok(html) => _ = chan.send(Frame { part, html }),
+                            Err(e) => error!("Could not construct HTML update: {e}"),
+                        }
+                    }
+                    last_frame = Instant::now();
+                }
+                last = current;
             },
-            else => { continue },
-        }
-        let html = update.render();
-        if let Ok(html) = html {
-            chan.send(html);
-        } else {
-            error!("Could not construct HTML update");
+            _ = crate::shutdown::requested(&mut shutdown_rx) => break,
         }
     }
-    unreachable!()
+    debug!("statemonitor stopped");
 }
 
 // // synchronous code can use the simple warp::Reply type
@@ -108,20 +242,55 @@ async fn statemonitor(mut update_rx: broadcast::Receiver<DownloaderMsg>, chan: U
 //     warp::reply::html(reply)
 // }
 
-/// Initiates resending the latest SSE message to all connected clients
-async fn kick(kick_chan: mpsc::Sender<()>) -> Result<impl warp::Reply, Infallible> {
-    kick_chan.send(()).await;
-    Ok(warp::reply::with_status(warp::reply(), warp::http::StatusCode::OK))
-}
-
-pub async fn test_root() -> Result<impl warp::Reply, Infallible> {
+pub async fn test_root(identity: auth::Identity) -> Result<impl warp::Reply, Infallible> {
     let h = Root {
+        csrf: identity.csrf.unwrap_or_default(),
     };
     let reply = h.render().unwrap();
     Ok(warp::reply::html(reply))
 }
 
-fn sse_test(chan: broadcast::Receiver<String>) -> impl Stream<Item = Result<Event, Infallible>> {
-    BroadcastStream::new(chan).map(|item| { Ok(Event::default().data(item.unwrap())) })
+/// Every part rendered from the current state
+fn snapshot(state_rx: &tokio::sync::watch::Receiver<Tracker>) -> Vec<Frame> {
+    let state = state_rx.borrow().clone();
+    Part::ALL
+        .into_iter()
+        .filter_map(|part| match part.render(&state) {
+            Ok(html) => Some(Frame { part, html }),
+            Err(e) => {
+                error!("Could not construct HTML update: {e}");
+                None
+            }
+        })
+        .collect()
 }
 
+/// Rendered updates for one client, starting with a snapshot. A client that
+/// falls behind skips the stale frames and gets a fresh snapshot.
+fn sse_test(chan: UpdateChan, state_rx: tokio::sync::watch::Receiver<Tracker>) -> impl Stream<Item = Result<Event, Infallible>> {
+    use broadcast::error::RecvError;
+    // subscribe first, so nothing between the snapshot and the stream is lost
+    let rx = chan.subscribe();
+    let pending: std::collections::VecDeque<Frame> = snapshot(&state_rx).into();
+    futures_util::stream::unfold((chan, rx, pending), move |(chan, mut rx, mut pending)| {
+        let state_rx = state_rx.clone();
+        async move {
+            let frame = loop {
+                if let Some(frame) = pending.pop_front() {
+                    break frame;
+                }
+                match rx.recv().await {
+                    Ok(frame) => break frame,
+                    Err(RecvError::Lagged(n)) => {
+                        debug!("SSE client lagged by {n} updates, sending the current state");
+                        rx = chan.subscribe();
+                        pending.extend(snapshot(&state_rx));
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            };
+            let event = Event::default().event(frame.part.name()).data(frame.html);
+            Some((Ok(event), (chan, rx, pending)))
+        }
+    })
+}
@@ -0,0 +1,415 @@
+//! Versioned JSON API under `/api/v1`. Every change goes through a
+//! [`DownloaderCommand`], reads are answered from the tracked state.
+use crate::commands::{CommandError, CommandRequest};
+use crate::history::{self, DeleteError, History};
+use crate::job::{Job, JobId, JobOptions, JobSpec};
+use crate::tracker::Tracker;
+use super::auth::{self, Auth, AuthError, Identity};
+use super::events::{self, EventLog};
+use crate::{DownloaderCommand, Settings, Url};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::convert::Infallible;
+use tokio::sync::{mpsc::UnboundedSender, watch};
+use warp::{
+    http::StatusCode,
+    reply::{self, Response},
+    Filter, Rejection, Reply,
+};
+
+/// Largest request body accepted, enough for a generous batch of URLs
+const BODY_LIMIT: u64 = 256 * 1024;
+
+#[derive(Clone)]
+pub(super) struct Api {
+    cmd_tx: UnboundedSender<CommandRequest>,
+    state_rx: watch::Receiver<Tracker>,
+    history: Option<History>,
+    settings_rx: watch::Receiver<Settings>,
+}
+
+#[derive(Debug)]
+pub(super) struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self { status, message: message.into() }
+    }
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+}
+
+impl Reply for ApiError {
+    fn into_response(self) -> Response {
+        let body = reply::json(&serde_json::json!({ "error": self.message }));
+        reply::with_status(body, self.status).into_response()
+    }
+}
+
+impl From<CommandError> for ApiError {
+    fn from(e: CommandError) -> Self {
+        let status = match e {
+            CommandError::NotFound => StatusCode::NOT_FOUND,
+            CommandError::InvalidState(_) => StatusCode::CONFLICT,
+            CommandError::Invalid(_) => StatusCode::BAD_REQUEST,
+        };
+        Self::new(status, e.to_string())
+    }
+}
+
+type ApiResult = Result<Response, ApiError>;
+
+fn respond(result: ApiResult) -> Response {
+    result.unwrap_or_else(Reply::into_response)
+}
+
+/// Changes to a queued job. Fields that are left out stay as they are.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobEdit {
+    url: Option<Url>,
+    options: Option<JobOptions>,
+}
+
+/// A past download to queue again, with other options if given
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Requeue {
+    options: Option<JobOptions>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A new position, or the job to move before
+struct MoveTo {
+    position: Option<usize>,
+    before: Option<JobId>,
+}
+
+impl Api {
+    pub fn new(
+        cmd_tx: UnboundedSender<CommandRequest>,
+        state_rx: watch::Receiver<Tracker>,
+        history: Option<History>,
+        settings_rx: watch::Receiver<Settings>,
+    ) -> Self {
+        Self { cmd_tx, state_rx, history, settings_rx }
+    }
+    /// Hands a command to the downloader and waits for the outcome
+    pub async fn send(&self, cmd: DownloaderCommand) -> Result<(), ApiError> {
+        let (request, reply_rx) = CommandRequest::new(cmd);
+        let unavailable = || ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "downloader is not running");
+        self.cmd_tx.send(request).map_err(|_| unavailable())?;
+        reply_rx.await.map_err(|_| unavailable())??;
+        Ok(())
+    }
+    pub fn state(&self) -> Tracker {
+        self.state_rx.borrow().clone()
+    }
+    pub fn settings(&self) -> Settings {
+        self.settings_rx.borrow().clone()
+    }
+    pub fn history(&self) -> Result<&History, ApiError> {
+        self.history
+            .as_ref()
+            .ok_or_else(|| ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "history is disabled"))
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> ApiResult {
+    Ok(reply::json(value).into_response())
+}
+
+fn created<T: Serialize>(value: &T) -> ApiResult {
+    Ok(reply::with_status(reply::json(value), StatusCode::CREATED).into_response())
+}
+
+fn no_content() -> ApiResult {
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn search_downloads(query: history::Query, api: Api) -> ApiResult {
+    let records = api.history()?.search(query).await.map_err(ApiError::bad_request)?;
+    json(&records)
+}
+
+async fn get_stats(api: Api) -> ApiResult {
+    let stats = api.history()?.stats().await.map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    json(&stats)
+}
+
+async fn get_download(id: i64, api: Api) -> ApiResult {
+    let record = api.history()?.get(id).await.map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    match record {
+        Some(record) => json(&record),
+        None => Err(ApiError::new(StatusCode::NOT_FOUND, "not found")),
+    }
+}
+
+async fn requeue_download(id: i64, requeue: Requeue, api: Api) -> ApiResult {
+    let record = api.history()?.get(id).await.map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let record = record.ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "not found"))?;
+    let spec = JobSpec {
+        url: record.url,
+        options: requeue.options.unwrap_or(record.options),
+    };
+    add_job(spec, api).await
+}
+
+async fn delete_download_file(id: i64, api: Api) -> ApiResult {
+    let root = api.settings_rx.borrow().downloader.output_dir.clone();
+    api.history()?.delete_file(id, root).await.map_err(|e| {
+        let status = match e {
+            DeleteError::NotFound => StatusCode::NOT_FOUND,
+            DeleteError::NoFile => StatusCode::CONFLICT,
+            DeleteError::Outside => StatusCode::FORBIDDEN,
+            DeleteError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError::new(status, e.to_string())
+    })?;
+    no_content()
+}
+
+async fn get_job(id: JobId, api: Api) -> ApiResult {
+    let state = api.state();
+    let job = state.current.iter().chain(&state.queue).find(|job| job.id == id);
+    match job {
+        Some(job) => json(job),
+        None => Err(CommandError::NotFound.into()),
+    }
+}
+
+async fn add_job(spec: JobSpec, api: Api) -> ApiResult {
+    let job = Job::from(spec);
+    api.send(DownloaderCommand::AddJobs(vec![job.clone()])).await?;
+    created(&job)
+}
+
+/// Adds all jobs or none of them
+async fn add_jobs(specs: Vec<JobSpec>, api: Api) -> ApiResult {
+    if specs.is_empty() {
+        return Err(ApiError::bad_request("no jobs given"));
+    }
+    let jobs: Vec<Job> = specs.into_iter().map(Job::from).collect();
+    api.send(DownloaderCommand::AddJobs(jobs.clone())).await?;
+    created(&jobs)
+}
+
+async fn edit_job(id: JobId, edit: JobEdit, api: Api) -> ApiResult {
+    let JobEdit { url, options } = edit;
+    api.send(DownloaderCommand::EditJob { id, url, options }).await?;
+    no_content()
+}
+
+async fn delete_job(id: JobId, api: Api) -> ApiResult {
+    api.send(DownloaderCommand::DeleteJob(id)).await?;
+    no_content()
+}
+
+async fn move_job(id: JobId, to: MoveTo, api: Api) -> ApiResult {
+    let cmd = match (to.position, to.before) {
+        (Some(position), None) => DownloaderCommand::MoveJob { id, position },
+        (None, Some(before)) => DownloaderCommand::MoveBefore { id, before },
+        _ => return Err(ApiError::bad_request("either position or before")),
+    };
+    api.send(cmd).await?;
+    no_content()
+}
+
+async fn control(cmd: DownloaderCommand, api: Api) -> ApiResult {
+    api.send(cmd).await?;
+    no_content()
+}
+
+/// Turns rejections into JSON errors, so clients never have to parse HTML
+/// or plain text
+async fn handle_rejection(err: Rejection) -> Result<Response, Infallible> {
+    use warp::filters::body::BodyDeserializeError;
+    use warp::reject::{InvalidQuery, MethodNotAllowed, PayloadTooLarge, UnsupportedMediaType};
+    if let Some(e) = err.find::<AuthError>() {
+        let response = ApiError::new(e.status(), e.to_string()).into_response();
+        if e.status() == StatusCode::UNAUTHORIZED {
+            return Ok(reply::with_header(response, "www-authenticate", auth::CHALLENGE).into_response());
+        }
+        return Ok(response);
+    }
+    let error = if err.is_not_found() {
+        ApiError::new(StatusCode::NOT_FOUND, "not found")
+    } else if let Some(e) = err.find::<BodyDeserializeError>() {
+        ApiError::bad_request(e.to_string())
+    } else if let Some(e) = err.find::<InvalidQuery>() {
+        ApiError::bad_request(e.to_string())
+    } else if err.find::<PayloadTooLarge>().is_some() {
+        ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "request body too large")
+    } else if err.find::<UnsupportedMediaType>().is_some() {
+        ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "expected application/json")
+    } else if err.find::<MethodNotAllowed>().is_some() {
+        ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+    } else {
+        crate::error!("Unhandled API rejection: {err:?}");
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+    };
+    Ok(error.into_response())
+}
+
+fn with_api(api: Api) -> impl Filter<Extract = (Api,), Error = Infallible> + Clone {
+    warp::any().map(move || api.clone())
+}
+
+fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    warp::body::content_length_limit(BODY_LIMIT).and(warp::body::json())
+}
+
+fn control_route(
+    name: &'static str,
+    cmd: DownloaderCommand,
+    api: Api,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path(name)
+        .and(warp::path::end())
+        .and(warp::post())
+        .map(move || cmd.clone())
+        .and(with_api(api))
+        .then(control)
+        .map(respond)
+}
+
+pub fn routes(
+    api: Api,
+    event_log: EventLog,
+    shutdown_rx: watch::Receiver<bool>,
+    auth: Auth,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let events = events::route(event_log, shutdown_rx).map(Reply::into_response);
+    let status = warp::path!("status")
+        .and(warp::get())
+        .and(with_api(api.clone()))
+        .map(|api: Api| respond(json(&api.state())));
+    let history = warp::path!("history")
+        .and(warp::get())
+        .and(with_api(api.clone()))
+        .map(|api: Api| respond(json(&api.state().history)));
+    let rate_history = warp::path!("rate_history")
+        .and(warp::get())
+        .and(with_api(api.clone()))
+        .map(|api: Api| respond(json(&api.state().rate_history)));
+    let downloads = warp::path!("downloads")
+        .and(warp::get())
+        .and(warp::query())
+        .and(with_api(api.clone()))
+        .then(search_downloads)
+        .map(respond);
+    let download = warp::path!("downloads" / i64)
+        .and(warp::get())
+        .and(with_api(api.clone()))
+        .then(get_download)
+        .map(respond);
+    let requeue = warp::path!("downloads" / i64 / "requeue")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_api(api.clone()))
+        .then(requeue_download)
+        .map(respond);
+    let delete_file = warp::path!("downloads" / i64 / "file")
+        .and(warp::delete())
+        .and(with_api(api.clone()))
+        .then(delete_download_file)
+        .map(respond);
+    let stats = warp::path!("stats")
+        .and(warp::get())
+        .and(with_api(api.clone()))
+        .then(get_stats)
+        .map(respond);
+    let list_jobs = warp::path!("jobs")
+        .and(warp::get())
+        .and(with_api(api.clone()))
+        .map(|api: Api| respond(json(&api.state().queue)));
+    let add_job = warp::path!("jobs")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_api(api.clone()))
+        .then(add_job)
+        .map(respond);
+    let add_batch = warp::path!("jobs" / "batch")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_api(api.clone()))
+        .then(add_jobs)
+        .map(respond);
+    let get_job = warp::path!("jobs" / JobId)
+        .and(warp::get())
+        .and(with_api(api.clone()))
+        .then(get_job)
+        .map(respond);
+    let edit_job = warp::path!("jobs" / JobId)
+        .and(warp::patch())
+        .and(json_body())
+        .and(with_api(api.clone()))
+        .then(edit_job)
+        .map(respond);
+    let delete_job = warp::path!("jobs" / JobId)
+        .and(warp::delete())
+        .and(with_api(api.clone()))
+        .then(delete_job)
+        .map(respond);
+    let move_job = warp::path!("jobs" / JobId / "move")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_api(api.clone()))
+        .then(move_job)
+        .map(respond);
+    // boxed in groups, one long chain of filters takes ages to compile
+    let reads = events
+        .or(status)
+        .unify()
+        .or(history)
+        .unify()
+        .or(rate_history)
+        .unify()
+        .or(downloads)
+        .unify()
+        .or(download)
+        .unify()
+        .or(stats)
+        .unify()
+        .or(list_jobs)
+        .unify()
+        .or(get_job)
+        .unify()
+        .boxed();
+    let changes = add_job
+        .or(add_batch)
+        .unify()
+        .or(requeue)
+        .unify()
+        .or(delete_file)
+        .unify()
+        .or(edit_job)
+        .unify()
+        .or(delete_job)
+        .unify()
+        .or(move_job)
+        .unify()
+        .boxed();
+    let controls = control_route("pause", DownloaderCommand::Pause, api.clone())
+        .or(control_route("resume", DownloaderCommand::Resume, api.clone()))
+        .unify()
+        .or(control_route("cancel", DownloaderCommand::Cancel, api.clone()))
+        .unify()
+        .or(control_route("retry", DownloaderCommand::Retry, api.clone()))
+        .unify()
+        .or(control_route("skip", DownloaderCommand::Skip, api))
+        .unify()
+        .boxed();
+    let v1 = reads.or(changes).unify().or(controls).unify();
+    let v1 = auth
+        .allow()
+        .and(v1)
+        .map(|_: Identity, response: Response| response)
+        .recover(handle_rejection)
+        .unify();
+    warp::path!("api" / "v1" / ..).and(v1)
+}
@@ -0,0 +1,517 @@
+//! Who may use the web server: bearer tokens for scripts, basic auth and a
+//! login form with session cookies for people. Sessions are kept in memory
+//! and end with the daemon. Requests authenticated by cookie have to repeat
+//! the session's CSRF token in a header to change anything.
+//!
+//! Password hashes are slow and big to check on purpose, so only a few checks
+//! run at a time, a user name that keeps failing has to wait longer and
+//! longer, and basic auth credentials that passed are remembered for a while.
+use crate::config::{AuthSettings, Scope};
+use argon2::{password_hash::PasswordVerifier, Argon2};
+use askama::Template;
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tokio::time::{Duration, Instant};
+use warp::{
+    http::{header, Method, StatusCode},
+    reply::{self, Response},
+    Filter, Rejection, Reply,
+};
+
+pub const SESSION_COOKIE: &str = "downd_session";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Sent with 401 responses from the API
+pub const CHALLENGE: &str = r#"Bearer, Basic realm="downd""#;
+/// Password checks running at once, each takes about 19 MiB
+const MAX_VERIFIES: usize = 2;
+/// How long basic auth credentials that passed skip the password check
+const BASIC_CACHE_TTL: Duration = Duration::from_secs(300);
+/// Failed logins of a user before they have to wait
+const FREE_FAILURES: u32 = 3;
+/// The longest wait after failed logins, it doubles up to this
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// No credentials, or an expired session
+    Missing,
+    /// Wrong token, user or password
+    Invalid,
+    Forbidden(&'static str),
+}
+
+impl warp::reject::Reject for AuthError {}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "authentication required"),
+            AuthError::Invalid => write!(f, "invalid credentials"),
+            AuthError::Forbidden(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl AuthError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AuthError::Missing | AuthError::Invalid => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden(_) => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+/// Who made a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub scope: Scope,
+    /// The session's CSRF token, for pages to hand to their scripts. Only
+    /// set for requests with a session cookie.
+    pub csrf: Option<String>,
+}
+
+struct Session {
+    scope: Scope,
+    csrf: String,
+    expires: Instant,
+}
+
+/// Failed password checks of a user
+struct Failures {
+    count: u32,
+    /// No checks before then
+    until: Instant,
+}
+
+#[derive(Clone)]
+pub struct Auth {
+    settings: Arc<AuthSettings>,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    /// Basic auth credentials that passed, with their scope and expiry
+    basic_cache: Arc<Mutex<HashMap<String, (Scope, Instant)>>>,
+    /// By user name, only for configured users
+    failures: Arc<Mutex<HashMap<String, Failures>>>,
+    verifying: Arc<Semaphore>,
+    /// Cookies are only sent over HTTPS
+    secure: bool,
+}
+
+/// Compares without giving away how much of a secret was right
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    getrandom::fill(&mut bytes).expect("system randomness is available");
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// GET and HEAD only read, everything else needs control
+fn required(method: &Method) -> Scope {
+    if method == Method::GET || method == Method::HEAD {
+        Scope::Read
+    } else {
+        Scope::Control
+    }
+}
+
+/// The credentials of a basic auth header
+fn basic_credentials(authorization: &str) -> Option<&str> {
+    let (scheme, credentials) = authorization.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("basic").then_some(credentials)
+}
+
+/// How long a user waits after `failures` failed logins in a row
+fn backoff(failures: u32) -> Duration {
+    match failures.checked_sub(FREE_FAILURES) {
+        None => Duration::ZERO,
+        Some(n) => Duration::from_secs(1u64 << n.min(16)).min(MAX_BACKOFF),
+    }
+}
+
+impl Auth {
+    pub fn new(settings: &AuthSettings, secure: bool) -> Self {
+        Self {
+            settings: Arc::new(settings.clone()),
+            sessions: Arc::default(),
+            basic_cache: Arc::default(),
+            failures: Arc::default(),
+            verifying: Arc::new(Semaphore::new(MAX_VERIFIES)),
+            secure,
+        }
+    }
+    pub fn enabled(&self) -> bool {
+        self.settings.enabled()
+    }
+    /// The scope of a bearer token, if it is one
+    pub fn token(&self, token: &str) -> Option<Scope> {
+        self.settings
+            .tokens
+            .iter()
+            .filter(|t| same(t.token.as_bytes(), token.as_bytes()))
+            .map(|t| t.scope)
+            .max()
+    }
+    /// Checks a password, off the async workers and a few at a time. Users
+    /// that failed too often are refused without a check until they waited.
+    async fn password(&self, user: &str, password: &str) -> Option<Scope> {
+        let user = self.settings.users.iter().find(|u| u.name == user)?.clone();
+        if self.failures.lock().unwrap().get(&user.name).is_some_and(|f| f.until > Instant::now()) {
+            return None;
+        }
+        let _permit = self.verifying.acquire().await.ok()?;
+        let hash = user.password_hash.clone();
+        let password = password.to_string();
+        let verified = tokio::task::spawn_blocking(move || {
+            Argon2::default().verify_password(password.as_bytes(), hash.as_str()).is_ok()
+        })
+        .await
+        .unwrap_or(false);
+        let mut failures = self.failures.lock().unwrap();
+        if verified {
+            failures.remove(&user.name);
+            return Some(user.scope);
+        }
+        let f = failures.entry(user.name).or_insert(Failures { count: 0, until: Instant::now() });
+        f.count += 1;
+        f.until = Instant::now() + backoff(f.count);
+        None
+    }
+    /// Checks basic auth credentials, remembering the ones that passed
+    async fn basic(&self, credentials: &str) -> Option<Scope> {
+        let credentials = credentials.trim();
+        if let Some(scope) = self.cached_basic(credentials) {
+            return Some(scope);
+        }
+        let decoded = base64::engine::general_purpose::STANDARD.decode(credentials).ok()?;
+        let (user, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+        let scope = self.password(user, password).await?;
+        let now = Instant::now();
+        let mut cache = self.basic_cache.lock().unwrap();
+        cache.retain(|_, (_, expires)| *expires > now);
+        cache.insert(credentials.to_string(), (scope, now + BASIC_CACHE_TTL));
+        Some(scope)
+    }
+    fn cached_basic(&self, credentials: &str) -> Option<Scope> {
+        let cache = self.basic_cache.lock().unwrap();
+        let (scope, expires) = cache.get(credentials.trim())?;
+        (*expires > Instant::now()).then_some(*scope)
+    }
+    fn session(&self, id: &str) -> Option<Identity> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.expires > now);
+        sessions.get(id).map(|s| Identity {
+            scope: s.scope,
+            csrf: Some(s.csrf.clone()),
+        })
+    }
+    /// Starts a session if the password is right, returns its id
+    pub async fn login(&self, user: &str, password: &str) -> Option<String> {
+        let scope = self.password(user, password).await?;
+        let id = random_token();
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.expires > now);
+        sessions.insert(
+            id.clone(),
+            Session {
+                scope,
+                csrf: random_token(),
+                expires: now + self.settings.session_ttl(),
+            },
+        );
+        Some(id)
+    }
+    pub fn logout(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+    /// Strict, other sites cannot make the browser send it
+    fn cookie(&self, value: &str, max_age: Duration) -> String {
+        let secure = if self.secure { "; Secure" } else { "" };
+        format!(
+            "{SESSION_COOKIE}={value}; Max-Age={}; Path=/; HttpOnly; SameSite=Strict{secure}",
+            max_age.as_secs()
+        )
+    }
+    /// Checks a request's credentials against the scope its method needs.
+    /// Basic auth only passes with credentials that [`Self::check`] has
+    /// verified recently.
+    fn authorize(
+        &self,
+        method: &Method,
+        authorization: Option<&str>,
+        cookie: Option<&str>,
+        csrf: Option<&str>,
+    ) -> Result<Identity, AuthError> {
+        if !self.enabled() {
+            return Ok(Identity { scope: Scope::Control, csrf: None });
+        }
+        let identity = if let Some(header) = authorization {
+            let (scheme, credentials) = header.split_once(' ').ok_or(AuthError::Invalid)?;
+            let scope = if scheme.eq_ignore_ascii_case("bearer") {
+                self.token(credentials.trim())
+            } else if scheme.eq_ignore_ascii_case("basic") {
+                self.cached_basic(credentials)
+            } else {
+                None
+            };
+            Identity { scope: scope.ok_or(AuthError::Invalid)?, csrf: None }
+        } else if let Some(id) = cookie {
+            let identity = self.session(id).ok_or(AuthError::Missing)?;
+            // other sites can make the browser send the cookie, but cannot
+            // read the token from our pages
+            let expected = identity.csrf.as_deref().unwrap_or_default();
+            if required(method) > Scope::Read && !csrf.is_some_and(|t| same(t.as_bytes(), expected.as_bytes())) {
+                return Err(AuthError::Forbidden("missing or wrong CSRF token"));
+            }
+            identity
+        } else {
+            return Err(AuthError::Missing);
+        };
+        if identity.scope < required(method) {
+            return Err(AuthError::Forbidden("read-only access"));
+        }
+        Ok(identity)
+    }
+    /// Passes requests that may use the route: GET and HEAD need the read
+    /// scope, everything else control
+    pub fn allow(&self) -> impl Filter<Extract = (Identity,), Error = Rejection> + Clone {
+        let auth = self.clone();
+        warp::method()
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+            .and(warp::header::optional::<String>(CSRF_HEADER))
+            .and_then(move |method: Method, authorization: Option<String>, cookie: Option<String>, csrf: Option<String>| {
+                let auth = auth.clone();
+                async move {
+                    auth.check(method, authorization, cookie, csrf)
+                        .await
+                        .map_err(warp::reject::custom)
+                }
+            })
+    }
+    /// [`Self::allow`], also taking a bearer token from a `token` query
+    /// parameter, for podcast apps and players that cannot set headers
+    pub fn allow_query_token(&self) -> impl Filter<Extract = (Identity,), Error = Rejection> + Clone {
+        let auth = self.clone();
+        warp::method()
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::query::<TokenParam>())
+            .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+            .and(warp::header::optional::<String>(CSRF_HEADER))
+            .and_then(move |method: Method, authorization: Option<String>, param: TokenParam, cookie: Option<String>, csrf: Option<String>| {
+                let auth = auth.clone();
+                let authorization = authorization.or_else(|| param.token.map(|t| format!("Bearer {t}")));
+                async move {
+                    auth.check(method, authorization, cookie, csrf)
+                        .await
+                        .map_err(warp::reject::custom)
+                }
+            })
+    }
+    /// [`Self::authorize`], checking basic auth passwords first
+    pub async fn check(
+        &self,
+        method: Method,
+        authorization: Option<String>,
+        cookie: Option<String>,
+        csrf: Option<String>,
+    ) -> Result<Identity, AuthError> {
+        let credentials = authorization.as_deref().filter(|_| self.enabled()).and_then(basic_credentials);
+        if let Some(credentials) = credentials {
+            self.basic(credentials).await.ok_or(AuthError::Invalid)?;
+        }
+        self.authorize(&method, authorization.as_deref(), cookie.as_deref(), csrf.as_deref())
+    }
+}
+
+/// The query parameter of [`Auth::allow_query_token`], others are ignored
+#[derive(Deserialize)]
+struct TokenParam {
+    token: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "login.html")]
+struct LoginPage<'a> {
+    error: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    user: String,
+    password: String,
+}
+
+fn login_page(error: Option<&str>, status: StatusCode) -> Response {
+    match (LoginPage { error }).render() {
+        Ok(html) => reply::with_status(reply::html(html), status).into_response(),
+        Err(e) => {
+            crate::error!("Could not render the login page: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn redirect(to: &'static str) -> Response {
+    reply::with_header(StatusCode::SEE_OTHER, header::LOCATION, to).into_response()
+}
+
+async fn login(form: LoginForm, auth: Auth) -> Response {
+    match auth.login(&form.user, &form.password).await {
+        Some(id) => {
+            let cookie = auth.cookie(&id, auth.settings.session_ttl());
+            reply::with_header(redirect("/root"), header::SET_COOKIE, cookie).into_response()
+        }
+        None => login_page(Some("Wrong user name or password"), StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// `/login` and `/logout`
+pub fn routes(auth: Auth) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let with_auth = {
+        let auth = auth.clone();
+        warp::any().map(move || auth.clone())
+    };
+    let form = warp::path!("login")
+        .and(warp::get())
+        .and(with_auth.clone())
+        .map(|auth: Auth| match auth.enabled() {
+            true => login_page(None, StatusCode::OK),
+            false => redirect("/root"),
+        });
+    let login = warp::path!("login")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::form())
+        .and(with_auth.clone())
+        .then(login);
+    let logout = warp::path!("logout")
+        .and(warp::post())
+        .and(auth.allow())
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .and(with_auth)
+        .map(|_: Identity, id: Option<String>, auth: Auth| {
+            if let Some(id) = id {
+                auth.logout(&id);
+            }
+            let cookie = auth.cookie("", Duration::ZERO);
+            reply::with_header(StatusCode::NO_CONTENT, header::SET_COOKIE, cookie).into_response()
+        });
+    form.or(login).unify().or(logout).unify()
+}
+
+/// Sends people without a session to the login form
+pub async fn login_redirect(err: Rejection) -> Result<Response, Rejection> {
+    match err.find::<AuthError>() {
+        Some(AuthError::Forbidden(reason)) => {
+            Ok(reply::with_status(reason.to_string(), StatusCode::FORBIDDEN).into_response())
+        }
+        Some(_) => Ok(redirect("/login")),
+        None => Err(err),
+    }
+}
+
+/// Answers failed authentication with a status and, for 401, a challenge,
+/// for clients that are not browsers
+pub async fn challenge(err: Rejection) -> Result<Response, Rejection> {
+    let Some(e) = err.find::<AuthError>() else {
+        return Err(err);
+    };
+    let response = reply::with_status(e.to_string(), e.status()).into_response();
+    Ok(match e.status() {
+        StatusCode::UNAUTHORIZED => reply::with_header(response, header::WWW_AUTHENTICATE, CHALLENGE).into_response(),
+        _ => response,
+    })
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+    use crate::config::{TokenSettings, UserSettings};
+    use argon2::{password_hash::PasswordHasher, Algorithm, Params, Version};
+
+    fn auth() -> Auth {
+        // cheap parameters, the defaults take seconds in debug builds
+        let params = Params::new(Params::MIN_M_COST, 1, 1, None).unwrap();
+        let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(b"secret")
+            .unwrap()
+            .to_string();
+        let settings = AuthSettings {
+            tokens: vec![TokenSettings { token: "read-token-0123456".into(), scope: Scope::Read }],
+            users: vec![UserSettings { name: "admin".into(), password_hash: hash, scope: Scope::Control }],
+            ..AuthSettings::default()
+        };
+        Auth::new(&settings, false)
+    }
+
+    #[tokio::test]
+    async fn check_tokens() {
+        let auth = auth();
+        let read = Identity { scope: Scope::Read, csrf: None };
+        assert_eq!(auth.authorize(&Method::GET, Some("Bearer read-token-0123456"), None, None), Ok(read));
+        assert_eq!(
+            auth.authorize(&Method::POST, Some("Bearer read-token-0123456"), None, None),
+            Err(AuthError::Forbidden("read-only access"))
+        );
+        assert_eq!(auth.authorize(&Method::GET, Some("Bearer nope"), None, None), Err(AuthError::Invalid));
+        assert_eq!(auth.authorize(&Method::GET, None, None, None), Err(AuthError::Missing));
+        // admin:secret and admin:wrong
+        let basic = |header: &str| auth.check(Method::POST, Some(header.into()), None, None);
+        assert_eq!(auth.authorize(&Method::POST, Some("Basic YWRtaW46c2VjcmV0"), None, None), Err(AuthError::Invalid));
+        assert!(basic("Basic YWRtaW46c2VjcmV0").await.is_ok());
+        // verified once, then remembered
+        assert!(auth.authorize(&Method::POST, Some("Basic YWRtaW46c2VjcmV0"), None, None).is_ok());
+        assert_eq!(basic("basic YWRtaW46d3Jvbmc=").await, Err(AuthError::Invalid));
+        let open = Auth::new(&AuthSettings::default(), false);
+        assert!(open.authorize(&Method::POST, None, None, None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn check_sessions() {
+        let auth = auth();
+        assert!(auth.login("admin", "wrong").await.is_none());
+        let id = auth.login("admin", "secret").await.unwrap();
+        let identity = auth.authorize(&Method::GET, None, Some(&id), None).unwrap();
+        let csrf = identity.csrf.unwrap();
+        assert_eq!(
+            auth.authorize(&Method::POST, None, Some(&id), None),
+            Err(AuthError::Forbidden("missing or wrong CSRF token"))
+        );
+        assert_eq!(
+            auth.authorize(&Method::POST, None, Some(&id), Some("guess")),
+            Err(AuthError::Forbidden("missing or wrong CSRF token"))
+        );
+        assert!(auth.authorize(&Method::POST, None, Some(&id), Some(&csrf)).is_ok());
+        auth.logout(&id);
+        assert_eq!(auth.authorize(&Method::GET, None, Some(&id), None), Err(AuthError::Missing));
+        tokio::time::pause();
+        let id = auth.login("admin", "secret").await.unwrap();
+        tokio::time::advance(auth.settings.session_ttl()).await;
+        assert_eq!(auth.authorize(&Method::GET, None, Some(&id), None), Err(AuthError::Missing));
+        assert!(auth.sessions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn check_backoff() {
+        assert_eq!(backoff(FREE_FAILURES - 1), Duration::ZERO);
+        assert_eq!(backoff(FREE_FAILURES + 2), Duration::from_secs(4));
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+        tokio::time::pause();
+        let auth = auth();
+        for _ in 0..FREE_FAILURES {
+            assert!(auth.login("admin", "wrong").await.is_none());
+        }
+        // even the right password has to wait
+        assert!(auth.login("admin", "secret").await.is_none());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(auth.login("admin", "secret").await.is_some());
+        assert!(auth.failures.lock().unwrap().is_empty());
+    }
+}
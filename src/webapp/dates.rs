@@ -0,0 +1,61 @@
+//! Unix seconds as the dates pages and feeds show, all in UTC
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Year, month and day from 1, and seconds into the day
+fn civil(secs: i64) -> (i64, i64, i64, i64) {
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // days to a civil date, after Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, secs)
+}
+
+/// "YYYY-MM-DD HH:MM"
+pub fn utc(secs: i64) -> String {
+    let (year, month, day, secs) = civil(secs);
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}", secs / 3600, secs % 3600 / 60)
+}
+
+/// For RSS, e.g. "Wed, 01 May 2024 12:00:00 GMT"
+pub fn rfc2822(secs: i64) -> String {
+    let (year, month, day, time) = civil(secs);
+    // the epoch was a Thursday
+    let weekday = WEEKDAYS[(secs.div_euclid(86400) + 4).rem_euclid(7) as usize];
+    let month = MONTHS[month as usize - 1];
+    format!(
+        "{weekday}, {day:02} {month} {year:04} {:02}:{:02}:{:02} GMT",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// For Atom, e.g. "2024-05-01T12:00:00Z"
+pub fn rfc3339(secs: i64) -> String {
+    let (year, month, day, time) = civil(secs);
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z", time / 3600, time % 3600 / 60, time % 60)
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+
+    #[test]
+    fn check_utc() {
+        assert_eq!(utc(0), "1970-01-01 00:00");
+        assert_eq!(utc(1714564800), "2024-05-01 12:00");
+        assert_eq!(utc(951782400 + 3599), "2000-02-29 00:59");
+        assert_eq!(utc(-60), "1969-12-31 23:59");
+        assert_eq!(rfc2822(1714564800 + 59), "Wed, 01 May 2024 12:00:59 GMT");
+        assert_eq!(rfc2822(-1), "Wed, 31 Dec 1969 23:59:59 GMT");
+        assert_eq!(rfc3339(951782400 + 3599), "2000-02-29T00:59:59Z");
+    }
+}
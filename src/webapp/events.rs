@@ -0,0 +1,301 @@
+//! Typed JSON events for `/api/v1/events`. Each event has an id of the form
+//! `<epoch>-<n>`, the epoch is random for every daemon start. Clients that
+//! reconnect with a `Last-Event-ID` get the events they missed, or a fresh
+//! snapshot if those are no longer kept.
+use crate::job::Job;
+use crate::tracker::{HistoryEntry, Tracker};
+use crate::{DaemonState, HoldReason};
+use futures_util::stream::{self, Stream};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, watch};
+use tracing::debug;
+use warp::{sse::Event, Filter, Rejection, Reply};
+
+/// Number of events kept for clients that reconnect
+const EVENT_LOG_LEN: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    pub progress: Option<f64>,
+    pub downloaded_bytes: u64,
+    pub total_bytes: Option<u64>,
+    pub rate: Option<u64>,
+    pub eta: Option<u64>,
+    /// Until the queue is done
+    pub queue_eta: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct State {
+    pub state: DaemonState,
+    pub title: Option<String>,
+    /// As in the status
+    pub hold: Option<String>,
+    pub current: Option<Job>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ApiEvent {
+    Progress(Progress),
+    State(State),
+    Queue(Vec<Job>),
+    JobFinished(HistoryEntry),
+    Error(String),
+}
+
+impl ApiEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            ApiEvent::Progress(_) => "progress",
+            ApiEvent::State(_) => "state",
+            ApiEvent::Queue(_) => "queue",
+            ApiEvent::JobFinished(_) => "job_finished",
+            ApiEvent::Error(_) => "error",
+        }
+    }
+    fn to_sse(&self, id: &str) -> Event {
+        let event = Event::default().id(id).event(self.name());
+        let data = match self {
+            ApiEvent::Progress(p) => event.json_data(p),
+            ApiEvent::State(s) => event.json_data(s),
+            ApiEvent::Queue(q) => event.json_data(q),
+            ApiEvent::JobFinished(h) => event.json_data(h),
+            ApiEvent::Error(message) => event.json_data(serde_json::json!({ "message": message })),
+        };
+        data.expect("events serialize to JSON")
+    }
+}
+
+fn progress(t: &Tracker) -> ApiEvent {
+    ApiEvent::Progress(Progress {
+        progress: t.progress,
+        downloaded_bytes: t.downloaded_bytes(),
+        total_bytes: t.total_bytes,
+        rate: t.rate(),
+        eta: t.eta,
+        queue_eta: t.queue_eta,
+    })
+}
+
+fn state(t: &Tracker) -> ApiEvent {
+    ApiEvent::State(State {
+        state: t.state.clone(),
+        title: t.title.clone(),
+        hold: t.hold.clone(),
+        current: t.current.clone(),
+    })
+}
+
+/// The events between two states of the tracker. Watchers may skip states,
+/// so finished jobs are counted rather than seen.
+fn events_between(old: &Tracker, new: &Tracker) -> Vec<ApiEvent> {
+    let mut events = vec![];
+    if (&old.state, &old.title, &old.hold, &old.current) != (&new.state, &new.title, &new.hold, &new.current) {
+        events.push(state(new));
+    }
+    let numbers = |t: &Tracker| (t.progress, t.downloaded_bytes(), t.total_bytes, t.rate(), t.eta, t.queue_eta);
+    if numbers(old) != numbers(new) {
+        events.push(progress(new));
+    }
+    if old.queue != new.queue {
+        events.push(ApiEvent::Queue(new.queue.clone()));
+    }
+    let ended = new.ended.saturating_sub(old.ended) as usize;
+    events.extend(new.history.iter().take(ended).rev().cloned().map(ApiEvent::JobFinished));
+    if old.state != new.state {
+        match &new.state {
+            DaemonState::Stuck => events.push(ApiEvent::Error("The downloader stopped making progress".into())),
+            DaemonState::Held { reason } if *reason != HoldReason::User => events.push(ApiEvent::Error(reason.to_string())),
+            _ => {}
+        }
+    }
+    events
+}
+
+type Numbered = (u64, ApiEvent);
+
+struct Inner {
+    tracker: Tracker,
+    last_id: u64,
+    log: VecDeque<Numbered>,
+    tx: broadcast::Sender<Numbered>,
+}
+
+/// Turns changes of the tracker into events and keeps the most recent ones
+#[derive(Clone)]
+pub struct EventLog {
+    epoch: u64,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl EventLog {
+    pub fn new(tracker: Tracker, capacity: usize) -> Self {
+        // random, so a restart within the same second cannot reuse ids
+        let mut bytes = [0; 8];
+        getrandom::fill(&mut bytes).expect("system randomness is available");
+        let epoch = u64::from_le_bytes(bytes);
+        let (tx, _) = broadcast::channel(capacity);
+        Self {
+            epoch,
+            inner: Arc::new(Mutex::new(Inner {
+                tracker,
+                last_id: 0,
+                log: VecDeque::new(),
+                tx,
+            })),
+        }
+    }
+    fn id(&self, n: u64) -> String {
+        format!("{}-{n}", self.epoch)
+    }
+    /// Parses an id handed out by this run of the daemon
+    fn parse_id(&self, id: &str) -> Option<u64> {
+        let (epoch, n) = id.trim().split_once('-')?;
+        (epoch.parse() == Ok(self.epoch)).then(|| n.parse().ok())?
+    }
+    fn publish(&self, tracker: &Tracker) {
+        let mut inner = self.inner.lock().unwrap();
+        let events = events_between(&inner.tracker, tracker);
+        inner.tracker = tracker.clone();
+        for event in events {
+            inner.last_id += 1;
+            let numbered = (inner.last_id, event);
+            if inner.log.len() == EVENT_LOG_LEN {
+                inner.log.pop_front();
+            }
+            inner.log.push_back(numbered.clone());
+            _ = inner.tx.send(numbered);
+        }
+    }
+    /// Clients following the events
+    pub fn clients(&self) -> usize {
+        self.inner.lock().unwrap().tx.receiver_count()
+    }
+    /// Subscribes to new events. Also returns what the client has to see
+    /// first: the events after `last_id`, or a snapshot of the whole state.
+    fn subscribe(&self, last_id: Option<u64>) -> (Vec<Numbered>, broadcast::Receiver<Numbered>) {
+        let inner = self.inner.lock().unwrap();
+        let rx = inner.tx.subscribe();
+        let oldest = inner.log.front().map_or(inner.last_id + 1, |(id, _)| *id);
+        let first = match last_id {
+            Some(id) if id <= inner.last_id && id + 1 >= oldest => {
+                inner.log.iter().filter(|(n, _)| *n > id).cloned().collect()
+            }
+            _ => {
+                let t = &inner.tracker;
+                [state(t), ApiEvent::Queue(t.queue.clone()), progress(t)]
+                    .into_iter()
+                    .map(|event| (inner.last_id, event))
+                    .collect()
+            }
+        };
+        (first, rx)
+    }
+}
+
+/// Feeds the event log until the tracker is gone
+pub async fn publisher(mut state_rx: watch::Receiver<Tracker>, log: EventLog) {
+    while state_rx.changed().await.is_ok() {
+        let tracker = state_rx.borrow_and_update().clone();
+        log.publish(&tracker);
+    }
+    debug!("event publisher stopped");
+}
+
+/// A client's events. A client that falls too far behind starts over with a
+/// snapshot.
+fn client_stream(log: EventLog, last_id: Option<u64>) -> impl Stream<Item = Result<Event, Infallible>> {
+    use broadcast::error::RecvError;
+    let (first, rx) = log.subscribe(last_id);
+    let pending: VecDeque<Numbered> = first.into();
+    stream::unfold((log, rx, pending), |(log, mut rx, mut pending)| async move {
+        loop {
+            if let Some((n, event)) = pending.pop_front() {
+                let sse = event.to_sse(&log.id(n));
+                return Some((Ok(sse), (log, rx, pending)));
+            }
+            match rx.recv().await {
+                Ok(numbered) => pending.push_back(numbered),
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("events client lagged by {skipped}, sending a snapshot");
+                    let (first, new_rx) = log.subscribe(None);
+                    rx = new_rx;
+                    pending = first.into();
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+pub fn route(
+    log: EventLog,
+    shutdown_rx: watch::Receiver<bool>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("events")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("last-event-id"))
+        .map(move |last_id: Option<String>| {
+            let last_id = last_id.and_then(|id| log.parse_id(&id));
+            let mut shutdown_rx = shutdown_rx.clone();
+            let stream = futures_util::StreamExt::take_until(client_stream(log.clone(), last_id), async move {
+                crate::shutdown::requested(&mut shutdown_rx).await
+            });
+            warp::sse::reply(warp::sse::keep_alive().stream(stream))
+        })
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+    use crate::job::JobOptions;
+    use crate::history::Status;
+    use crate::DownloaderMsg;
+
+    #[test]
+    fn check_replay() {
+        let mut t = Tracker::new();
+        let log = EventLog::new(t.clone(), 16);
+        let job = |n: u8| Job::new(format!("https://example.com/{n}").as_str(), JobOptions::default());
+        t.update(DownloaderMsg::QueueUpdate(vec![job(1)]));
+        log.publish(&t);
+        t.update(DownloaderMsg::Current(job(1)));
+        t.update(DownloaderMsg::QueueUpdate(vec![]));
+        log.publish(&t);
+        // queue, then state and queue
+        let (replay, _) = log.subscribe(Some(1));
+        assert_eq!(replay.iter().map(|(n, e)| (*n, e.name())).collect::<Vec<_>>(), [(2, "state"), (3, "queue")]);
+        let (replay, _) = log.subscribe(Some(3));
+        assert!(replay.is_empty());
+        // unknown ids get a snapshot
+        let (snapshot, _) = log.subscribe(Some(9));
+        assert_eq!(snapshot.iter().map(|(_, e)| e.name()).collect::<Vec<_>>(), ["state", "queue", "progress"]);
+    }
+
+    #[test]
+    fn check_between() {
+        let job = Job::new("https://example.com/1", JobOptions::default());
+        let old = Tracker::new();
+        let mut new = old.clone();
+        // a watcher that missed the start of the job
+        new.update(DownloaderMsg::Current(job.clone()));
+        new.update(DownloaderMsg::Finished { job: job.clone(), status: Status::Finished, reason: "Finished".into() });
+        new.update(DownloaderMsg::Finished { job, status: Status::Skipped, reason: "Skipped".into() });
+        new.update(DownloaderMsg::StateChanged(DaemonState::Stuck));
+        let events = events_between(&old, &new);
+        assert_eq!(events.iter().map(ApiEvent::name).collect::<Vec<_>>(), ["state", "job_finished", "job_finished", "error"]);
+        assert!(matches!(&events[1], ApiEvent::JobFinished(h) if h.status == Status::Finished));
+        assert!(events_between(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn check_ids() {
+        let log = EventLog::new(Tracker::new(), 16);
+        assert_eq!(log.parse_id(&log.id(12)), Some(12));
+        assert_eq!(log.parse_id("1-12"), None);
+        assert_eq!(log.parse_id("garbage"), None);
+    }
+}
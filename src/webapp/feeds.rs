@@ -0,0 +1,270 @@
+//! `/feeds/rss` and `/feeds/atom`: finished downloads as podcast feeds. The
+//! files are linked as enclosures under `/files/`, so feeds are only served
+//! along with them, and only with a configured `web.base_url` to link to.
+use super::api::Api;
+use super::auth::{Auth, Identity};
+use super::dates::{rfc2822, rfc3339};
+use super::files;
+use crate::config::Scope;
+use crate::history::{Query, Record, Status};
+use askama::Template;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use warp::{
+    http::{header, StatusCode},
+    reply::{self, Response},
+    Filter, Rejection, Reply,
+};
+
+/// Newest downloads in a feed
+const FEED_ITEMS: u32 = 100;
+
+#[derive(Debug, Default, Deserialize)]
+struct FeedParams {
+    /// The folder the downloads were saved in, see `subdir` of the job
+    /// options
+    category: Option<String>,
+    text: Option<String>,
+    /// Added to the enclosure links as well, for apps that only know URLs.
+    /// Only read tokens, the links end up in apps, their logs and shared
+    /// feeds.
+    token: Option<String>,
+}
+
+impl FeedParams {
+    fn query(&self) -> Query {
+        Query {
+            text: self.text.clone().filter(|t| !t.is_empty()),
+            status: Some(Status::Finished),
+            subdir: self.category.clone().filter(|c| !c.is_empty()),
+            limit: Some(FEED_ITEMS),
+            ..Query::default()
+        }
+    }
+    /// The feed's own query string, without the token
+    fn query_string(&self) -> String {
+        let pairs = [("category", &self.category), ("text", &self.text)];
+        let pairs: Vec<_> = pairs
+            .into_iter()
+            .filter_map(|(name, value)| value.as_deref().filter(|v| !v.is_empty()).map(|v| (name, v)))
+            .map(|(name, value)| format!("{name}={}", utf8_percent_encode(value, NON_ALPHANUMERIC)))
+            .collect();
+        match pairs.is_empty() {
+            true => String::new(),
+            false => format!("?{}", pairs.join("&")),
+        }
+    }
+}
+
+/// Characters XML 1.0 does not allow, even escaped, are left out
+fn xml_text(text: &str) -> String {
+    text.chars().filter(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r')).collect()
+}
+
+/// A finished download whose file is still there
+struct Item {
+    id: i64,
+    title: String,
+    description: String,
+    /// The page it was downloaded from
+    link: String,
+    enclosure: String,
+    length: u64,
+    mime: String,
+    /// Whole seconds
+    duration: Option<u64>,
+    category: Option<String>,
+    finished: i64,
+}
+
+impl Item {
+    fn pub_date(&self) -> String {
+        rfc2822(self.finished)
+    }
+    fn updated(&self) -> String {
+        rfc3339(self.finished)
+    }
+}
+
+/// None if the file was deleted or is not inside the canonical `root`
+fn item(record: Record, root: &Path, base: &str, token: Option<&str>) -> Option<Item> {
+    if record.deleted.is_some() {
+        return None;
+    }
+    let path = Path::new(record.path.as_deref()?).canonicalize().ok()?;
+    let meta = std::fs::metadata(&path).ok().filter(|m| m.is_file())?;
+    let mut enclosure = format!("{base}{}", files::link(root, &path)?);
+    if let Some(token) = token {
+        enclosure.push_str("?token=");
+        enclosure.extend(utf8_percent_encode(token, NON_ALPHANUMERIC));
+    }
+    Some(Item {
+        id: record.id,
+        title: xml_text(record.title.as_deref().unwrap_or(&record.url)),
+        description: xml_text(record.description.as_deref().unwrap_or_default()),
+        link: record.url,
+        enclosure,
+        length: meta.len(),
+        mime: mime_guess::from_path(&path).first_or_octet_stream().to_string(),
+        duration: record.duration.filter(|d| *d >= 0.0).map(|d| d.round() as u64),
+        category: record.options.subdir,
+        finished: record.finished,
+    })
+}
+
+/// What both formats show
+struct Feed {
+    title: String,
+    /// `web.base_url`, e.g. `http://nas.local:3000`
+    base: String,
+    /// The URL of the feed itself
+    url: String,
+    items: Vec<Item>,
+}
+
+impl Feed {
+    /// The newest item, or now for an empty feed
+    fn updated(&self) -> i64 {
+        self.items.first().map(|i| i.finished).unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+        })
+    }
+    fn pub_date(&self) -> String {
+        rfc2822(self.updated())
+    }
+    fn updated_rfc3339(&self) -> String {
+        rfc3339(self.updated())
+    }
+}
+
+#[derive(Template)]
+#[template(path = "feed_rss.xml")]
+struct Rss<'a> {
+    feed: &'a Feed,
+}
+
+#[derive(Template)]
+#[template(path = "feed_atom.xml")]
+struct Atom<'a> {
+    feed: &'a Feed,
+}
+
+#[derive(Clone)]
+struct Ctx {
+    api: Api,
+    auth: Auth,
+    root: Arc<PathBuf>,
+    base: Arc<str>,
+}
+
+async fn feed(
+    format: String,
+    _: Identity,
+    params: FeedParams,
+    ctx: Ctx,
+) -> Result<Response, Rejection> {
+    let content_type = match format.as_str() {
+        "rss" => "application/rss+xml; charset=utf-8",
+        "atom" => "application/atom+xml; charset=utf-8",
+        _ => return Err(warp::reject::not_found()),
+    };
+    let token = params.token.clone().filter(|t| !t.is_empty() && ctx.auth.enabled());
+    if token.as_deref().is_some_and(|t| ctx.auth.token(t) != Some(Scope::Read)) {
+        return Ok(reply::with_status("feeds only take read tokens", StatusCode::FORBIDDEN).into_response());
+    }
+    let history = match ctx.api.history() {
+        Ok(history) => history,
+        Err(e) => return Ok(e.into_response()),
+    };
+    let records = match history.search(params.query()).await {
+        Ok(records) => records,
+        Err(e) => {
+            crate::error!("Could not read the history for a feed: {e}");
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    let base = ctx.base.to_string();
+    let title = match params.category.as_deref().filter(|c| !c.is_empty()) {
+        Some(category) => format!("downd: {}", xml_text(category)),
+        None => "downd".to_string(),
+    };
+    let url = format!("{base}/feeds/{format}{}", params.query_string());
+    let links = base.clone();
+    let items = tokio::task::spawn_blocking(move || {
+        let Ok(root) = ctx.root.canonicalize() else {
+            return vec![];
+        };
+        records.into_iter().filter_map(|r| item(r, &root, &links, token.as_deref())).collect()
+    })
+    .await
+    .unwrap_or_default();
+    let feed = Feed { title, base, url, items };
+    let xml = match format.as_str() {
+        "rss" => Rss { feed: &feed }.render(),
+        _ => Atom { feed: &feed }.render(),
+    };
+    Ok(match xml {
+        Ok(xml) => reply::with_header(xml, header::CONTENT_TYPE, content_type).into_response(),
+        Err(e) => {
+            crate::error!("Could not render a feed: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    })
+}
+
+/// `/feeds/rss` and `/feeds/atom`, with the files of `root` linked below
+/// `base`
+pub fn routes(
+    api: Api,
+    root: PathBuf,
+    auth: Auth,
+    base: String,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let ctx = Ctx { api, auth: auth.clone(), root: Arc::new(root), base: base.into() };
+    warp::path!("feeds" / String)
+        .and(warp::get().or(warp::head()).unify())
+        .and(auth.allow_query_token())
+        .and(warp::query::<FeedParams>())
+        .and(warp::any().map(move || ctx.clone()))
+        .and_then(feed)
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+    use crate::history::checks::record;
+    use crate::job::JobOptions;
+
+    #[test]
+    fn check_item() {
+        let root = std::env::temp_dir().join(format!("downd-feeds-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("talks")).unwrap();
+        std::fs::create_dir_all(root.join("other")).unwrap();
+        std::fs::write(root.join("talks/a talk.mp3"), "12345").unwrap();
+        let root = root.canonicalize().unwrap();
+        let record = Record {
+            id: 7,
+            title: Some("A talk\u{1}".into()),
+            path: Some(root.join("talks/a talk.mp3").to_string_lossy().into()),
+            bytes: 5,
+            options: JobOptions { subdir: Some("talks".into()), ..JobOptions::default() },
+            duration: Some(61.6),
+            ..record("https://a.example/talk", 1714564800, Status::Finished)
+        };
+        let item = item(record.clone(), &root, "http://nas:3000", Some("a b")).unwrap();
+        assert_eq!(item.title, "A talk");
+        assert_eq!(item.enclosure, "http://nas:3000/files/talks/a%20talk.mp3?token=a%20b");
+        assert_eq!((item.length, item.mime.as_str(), item.duration), (5, "audio/mpeg", Some(62)));
+        assert_eq!(item.category.as_deref(), Some("talks"));
+        let deleted = Record { deleted: Some(1), ..record.clone() };
+        assert!(super::item(deleted, &root, "", None).is_none());
+        assert!(super::item(record, &root.join("other"), "", None).is_none());
+        std::fs::remove_dir_all(root).unwrap();
+        let params = FeedParams { category: Some("a&b".into()), token: Some("x".into()), ..FeedParams::default() };
+        assert_eq!(params.query_string(), "?category=a%26b");
+        assert_eq!(params.query().subdir.as_deref(), Some("a&b"));
+    }
+}
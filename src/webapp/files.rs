@@ -0,0 +1,202 @@
+//! `/files/`: the output directory in the browser. Directories are listed
+//! here, files are served by warp, which handles ranges, MIME types and
+//! caching headers.
+use super::auth::{Auth, Identity};
+use super::send::render;
+use crate::humanize_bytes;
+use askama::Template;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use warp::{
+    http::StatusCode,
+    path::Peek,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+/// Escaped in a path segment of a link
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'\\')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// The file or directory a request path points to. None if it does not
+/// exist or is not inside `root`, also by way of a link.
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut full = root.to_path_buf();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        // still one plain name after decoding, so no `..` or `a%2Fb`
+        let mut components = Path::new(segment.as_ref()).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => full.push(name),
+            _ => return None,
+        }
+    }
+    let full = full.canonicalize().ok()?;
+    full.starts_with(root.canonicalize().ok()?).then_some(full)
+}
+
+/// The `/files/` URL of a path inside `root`
+pub fn link(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let mut link = String::from("/files");
+    for component in relative.components() {
+        let Component::Normal(name) = component else {
+            return None;
+        };
+        link.push('/');
+        link.extend(utf8_percent_encode(&name.to_string_lossy(), SEGMENT));
+    }
+    Some(link)
+}
+
+struct Entry {
+    name: String,
+    href: String,
+    dir: bool,
+    size: String,
+}
+
+#[derive(Template)]
+#[template(path = "files.html")]
+struct Listing {
+    /// Relative to the root, e.g. `/music/`
+    path: String,
+    parent: Option<String>,
+    entries: Vec<Entry>,
+}
+
+/// Directories first, hidden files left out
+fn list(root: &Path, dir: &Path) -> std::io::Result<Listing> {
+    let root = root.canonicalize()?;
+    let mut entries = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        // links are followed, and only shown if they stay inside
+        let Ok(target) = entry.path().canonicalize() else {
+            continue;
+        };
+        if !target.starts_with(&root) {
+            continue;
+        }
+        let Ok(meta) = std::fs::metadata(&target) else {
+            continue;
+        };
+        let Some(mut href) = link(&root, &dir.join(&name)) else {
+            continue;
+        };
+        if meta.is_dir() {
+            href.push('/');
+        }
+        entries.push(Entry {
+            name,
+            href,
+            dir: meta.is_dir(),
+            size: if meta.is_dir() { String::new() } else { humanize_bytes(meta.len()) },
+        });
+    }
+    entries.sort_by(|a, b| b.dir.cmp(&a.dir).then_with(|| a.name.cmp(&b.name)));
+    let relative = dir.strip_prefix(&root).unwrap_or(Path::new(""));
+    let parent = relative
+        .parent()
+        .and_then(|p| link(&root, &root.join(p)))
+        .map(|href| format!("{href}/"));
+    let mut path = format!("/{}", relative.display());
+    if !path.ends_with('/') {
+        path.push('/');
+    }
+    Ok(Listing { path, parent, entries })
+}
+
+/// A listing for directories, None for files, which are left to warp
+async fn check(_: Identity, peek: Peek, root: Arc<PathBuf>) -> Result<Option<Response>, Rejection> {
+    let path = peek.as_str().to_string();
+    let listing = tokio::task::spawn_blocking(move || {
+        let full = resolve(&root, &path)?;
+        if !full.is_dir() {
+            return Some(None);
+        }
+        Some(Some(list(&root, &full)))
+    })
+    .await
+    .ok()
+    .flatten()
+    .ok_or_else(warp::reject::not_found)?;
+    Ok(listing.map(|listing| match listing {
+        Ok(listing) => render(listing, StatusCode::OK),
+        Err(e) => {
+            crate::warn!("Could not list a download directory: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }))
+}
+
+/// `/files/...` over `root`. Every request is checked before warp gets to
+/// serve it, warp's own checks do not cover links. Feeds hand out links with
+/// a `token` parameter, so that is accepted too.
+pub fn routes(root: PathBuf, auth: Auth) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let file = warp::fs::dir(root.clone())
+        .map(|file: warp::fs::File| Some(file.into_response()))
+        .or(warp::any().map(|| None))
+        .unify();
+    let root = Arc::new(root);
+    warp::path("files")
+        .and(warp::get().or(warp::head()).unify())
+        .and(auth.allow_query_token())
+        .and(warp::path::peek())
+        .and(warp::any().map(move || root.clone()))
+        .and_then(check)
+        .and(file)
+        .and_then(|listing: Option<Response>, file: Option<Response>| async move {
+            listing.or(file).ok_or_else(warp::reject::not_found)
+        })
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+
+    #[test]
+    fn check_resolve() {
+        let base = std::env::temp_dir().join(format!("downd-files-{}", std::process::id()));
+        let root = base.join("out");
+        std::fs::create_dir_all(root.join("a b")).unwrap();
+        std::fs::write(root.join("a b/c#1.mp4"), "x").unwrap();
+        std::fs::write(base.join("secret"), "x").unwrap();
+        std::os::unix::fs::symlink(base.join("secret"), root.join("link")).unwrap();
+        let root = root.canonicalize().unwrap();
+        assert_eq!(resolve(&root, "a%20b/c%231.mp4"), Some(root.join("a b/c#1.mp4")));
+        assert_eq!(resolve(&root, ""), Some(root.clone()));
+        assert_eq!(resolve(&root, "a%20b/../../secret"), None);
+        assert_eq!(resolve(&root, "%2E%2E/secret"), None);
+        assert_eq!(resolve(&root, "..%2Fsecret"), None);
+        assert_eq!(resolve(&root, "link"), None);
+        assert_eq!(resolve(&root, "missing"), None);
+        assert_eq!(link(&root, &root.join("a b/c#1.mp4")).unwrap(), "/files/a%20b/c%231.mp4");
+        assert_eq!(link(&root, &base.join("secret")), None);
+        let listing = list(&root, &root.join("a b")).unwrap();
+        assert_eq!(listing.path, "/a b/");
+        assert_eq!(listing.parent.as_deref(), Some("/files/"));
+        assert_eq!(listing.entries[0].href, "/files/a%20b/c%231.mp4");
+        let names: Vec<_> = list(&root, &root).unwrap().entries.into_iter().map(|e| e.name).collect();
+        assert_eq!(names, ["a b"]);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+}
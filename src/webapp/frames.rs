@@ -0,0 +1,137 @@
+//! The page is updated in parts, so a progress tick does not resend the queue
+use crate::job::Job;
+use crate::tracker::{RateHistory, Tracker};
+use crate::DaemonState;
+use askama::Template;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Part {
+    State,
+    Progress,
+    Queue,
+}
+
+impl Part {
+    pub const COUNT: usize = 3;
+    pub const ALL: [Part; Part::COUNT] = [Part::State, Part::Progress, Part::Queue];
+
+    /// The SSE event name, and the id of the element it replaces
+    pub fn name(self) -> &'static str {
+        match self {
+            Part::State => "state",
+            Part::Progress => "progress",
+            Part::Queue => "queue",
+        }
+    }
+    pub fn render(self, t: &Tracker) -> askama::Result<String> {
+        match self {
+            Part::State => StatePart {
+                state: &t.state,
+                title: &t.title,
+                current: &t.current,
+            }
+            .render(),
+            Part::Progress => ProgressPart {
+                progress: &t.progress,
+                rate_h: &t.rate_h,
+                eta: &t.eta,
+                queue_eta: &t.queue_eta,
+                rate_history: &t.rate_history,
+            }
+            .render(),
+            Part::Queue => QueuePart { queue: &t.queue }.render(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub part: Part,
+    pub html: String,
+}
+
+#[derive(Template)]
+#[template(path = "parts/state.html")]
+struct StatePart<'a> {
+    state: &'a DaemonState,
+    title: &'a Option<String>,
+    current: &'a Option<Job>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/progress.html")]
+struct ProgressPart<'a> {
+    progress: &'a Option<f64>,
+    rate_h: &'a Option<String>,
+    eta: &'a Option<u64>,
+    queue_eta: &'a Option<u64>,
+    rate_history: &'a RateHistory,
+}
+
+#[derive(Template)]
+#[template(path = "parts/queue.html")]
+struct QueuePart<'a> {
+    queue: &'a [Job],
+}
+
+/// Parts that changed since the last frame
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Dirty([bool; Part::COUNT]);
+
+impl Dirty {
+    pub fn any(&self) -> bool {
+        self.0.contains(&true)
+    }
+    fn mark_part(&mut self, part: Part) {
+        self.0[part as usize] = true;
+    }
+    /// The parts that differ between two states of the tracker
+    pub fn between(old: &Tracker, new: &Tracker) -> Self {
+        let mut dirty = Self::default();
+        if (&old.state, &old.title, &old.current) != (&new.state, &new.title, &new.current) {
+            dirty.mark_part(Part::State);
+        }
+        if (old.progress, &old.rate_h, old.eta, old.queue_eta) != (new.progress, &new.rate_h, new.eta, new.queue_eta)
+            || old.rate_history != new.rate_history
+        {
+            dirty.mark_part(Part::Progress);
+        }
+        if old.queue != new.queue {
+            dirty.mark_part(Part::Queue);
+        }
+        dirty
+    }
+    /// The changed parts, leaving nothing marked
+    pub fn take(&mut self) -> Vec<Part> {
+        let parts = Part::ALL.into_iter().filter(|p| self.0[*p as usize]).collect();
+        *self = Self::default();
+        parts
+    }
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+    use crate::DownloaderMsg;
+
+    #[test]
+    fn check_dirty() {
+        let old = Tracker::new();
+        assert!(!Dirty::between(&old, &old).any());
+        let mut new = old.clone();
+        new.update(DownloaderMsg::QueueUpdate(vec![]));
+        new.update(DownloaderMsg::StateChanged(DaemonState::Stuck));
+        assert_eq!(Dirty::between(&old, &new).take(), [Part::State]);
+        let job = Job::new("https://example.com/1", crate::job::JobOptions::default());
+        new.update(DownloaderMsg::QueueUpdate(vec![job]));
+        let mut dirty = Dirty::between(&old, &new);
+        assert_eq!(dirty.take(), [Part::State, Part::Queue]);
+        assert!(!dirty.any());
+    }
+
+    #[test]
+    fn check_render() {
+        let html = Part::Queue.render(&Tracker::new()).unwrap();
+        assert!(html.contains(r#"<ul id="queue">"#));
+    }
+}
@@ -0,0 +1,217 @@
+//! `/healthz` and `/readyz` for service managers and load balancers, and
+//! `/debug/state` to look inside a daemon that seems to hang
+use super::api::Api;
+use super::auth::{self, Auth, Identity};
+use super::events::EventLog;
+use super::UpdateChan;
+use crate::commands::DownloaderCommand;
+use crate::config::{DownloaderSettings, Scope};
+use crate::downloader::Internals;
+use crate::DownloaderMsg;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use tokio::sync::{broadcast, watch};
+use tokio::time::{Duration, Instant};
+use warp::{
+    http::StatusCode,
+    reply::{self, Response},
+    Filter, Rejection, Reply,
+};
+
+/// How long the downloader gets to answer a ping
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The downloader's side of things, for the web server to watch
+pub struct Probes {
+    pub update_tx: broadcast::Sender<DownloaderMsg>,
+    pub internals_rx: watch::Receiver<Internals>,
+}
+
+/// Milliseconds for a ping to go through the command channel and back
+async fn ping(api: &Api) -> Result<u128, String> {
+    let start = Instant::now();
+    match tokio::time::timeout(PING_TIMEOUT, api.send(DownloaderCommand::Ping)).await {
+        Ok(Ok(())) => Ok(start.elapsed().as_millis()),
+        Ok(Err(e)) => Err(e.message),
+        Err(_) => Err(format!("no answer within {}s", PING_TIMEOUT.as_secs())),
+    }
+}
+
+async fn healthz(api: Api) -> Response {
+    match ping(&api).await {
+        Ok(ms) => reply::json(&json!({ "status": "ok", "round_trip_ms": ms })).into_response(),
+        Err(e) => {
+            crate::warn!("Health check failed: {e}");
+            let body = reply::json(&json!({ "status": "unresponsive", "error": e }));
+            reply::with_status(body, StatusCode::SERVICE_UNAVAILABLE).into_response()
+        }
+    }
+}
+
+/// Where a command name is found, like the shell would
+fn find_binary(binary: &Path) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+    let executable = |path: &Path| std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0);
+    if binary.components().count() > 1 {
+        return executable(binary).then(|| binary.to_path_buf());
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).map(|dir| dir.join(binary)).find(|p| executable(p))
+}
+
+/// Creates and removes a file in `dir`
+fn writable(dir: &Path) -> Result<(), String> {
+    let probe = dir.join(format!(".downd-ready-{}", std::process::id()));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .map_err(|e| format!("{}: {e}", dir.display()))?;
+    _ = std::fs::remove_file(probe);
+    Ok(())
+}
+
+/// Whether the downloader binary is found and the output directory writable
+fn readiness(settings: &DownloaderSettings) -> Result<(), String> {
+    let binary = match find_binary(&settings.binary) {
+        Some(_) => Ok(()),
+        None => Err(format!("{} is not an executable file", settings.binary.display())),
+    };
+    // yt-dlp saves to its working directory without one
+    let dir = match &settings.output_dir {
+        Some(dir) => Ok(dir.clone()),
+        None => std::env::current_dir().map_err(|e| e.to_string()),
+    };
+    binary.and(dir.and_then(|dir| writable(&dir)))
+}
+
+async fn readyz(api: Api) -> Response {
+    let settings = api.settings().downloader;
+    let ready = tokio::task::spawn_blocking(move || readiness(&settings))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    // the reason goes to the log, the endpoint is open to anyone
+    match ready {
+        Ok(()) => reply::with_status("ok", StatusCode::OK).into_response(),
+        Err(e) => {
+            crate::warn!("Not ready: {e}");
+            reply::with_status("not ready", StatusCode::SERVICE_UNAVAILABLE).into_response()
+        }
+    }
+}
+
+/// Seconds until `deadline`, negative once it passed
+fn secs_until(deadline: Option<Instant>, now: Instant) -> Option<f64> {
+    deadline.map(|d| match d.checked_duration_since(now) {
+        Some(left) => left.as_secs_f64(),
+        None => -now.duration_since(d).as_secs_f64(),
+    })
+}
+
+#[derive(Clone)]
+struct Ctx {
+    api: Api,
+    update_tx: broadcast::Sender<DownloaderMsg>,
+    internals_rx: watch::Receiver<Internals>,
+    pages: UpdateChan,
+    events: EventLog,
+}
+
+async fn debug_state(identity: Identity, ctx: Ctx) -> Response {
+    if identity.scope < Scope::Control {
+        return reply::with_status("needs the control scope", StatusCode::FORBIDDEN).into_response();
+    }
+    let round_trip = ping(&ctx.api).await;
+    let internals = ctx.internals_rx.borrow().clone();
+    let state = ctx.api.state();
+    let now = Instant::now();
+    reply::json(&json!({
+        "downloader": {
+            "phase": internals.phase,
+            "pid": internals.pid,
+            "stuck_in_secs": secs_until(internals.stuck_at, now),
+            "stop_in_secs": secs_until(internals.stop_at, now),
+        },
+        "state": state.state,
+        "current": state.current,
+        "queue": state.queue,
+        "channels": {
+            "updates": {
+                "queued": ctx.update_tx.len(),
+                "capacity": ctx.api.settings().downloader.update_buffer,
+                "subscribers": ctx.update_tx.receiver_count(),
+            },
+            "commands": {
+                "round_trip_ms": round_trip.as_ref().ok(),
+                "error": round_trip.err(),
+            },
+        },
+        "clients": {
+            "pages": ctx.pages.receiver_count(),
+            "events": ctx.events.clients(),
+        },
+    }))
+    .into_response()
+}
+
+/// `/healthz` and `/readyz` are open to anyone and tell nothing but the
+/// outcome. `/debug/state` needs the control scope.
+pub fn routes(
+    api: Api,
+    probes: &Probes,
+    pages: UpdateChan,
+    events: EventLog,
+    auth: Auth,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let with_api = {
+        let api = api.clone();
+        warp::any().map(move || api.clone())
+    };
+    let healthz = warp::path!("healthz").and(warp::get()).and(with_api.clone()).then(healthz);
+    let readyz = warp::path!("readyz").and(warp::get()).and(with_api).then(readyz);
+    let ctx = Ctx {
+        api,
+        update_tx: probes.update_tx.clone(),
+        internals_rx: probes.internals_rx.clone(),
+        pages,
+        events,
+    };
+    let debug = warp::path!("debug" / "state")
+        .and(warp::get())
+        .and(auth.allow())
+        .and(warp::any().map(move || ctx.clone()))
+        .then(debug_state)
+        .recover(auth::challenge)
+        .unify();
+    healthz.or(readyz).unify().or(debug).unify()
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+
+    #[test]
+    fn check_readiness() {
+        let dir = std::env::temp_dir().join(format!("downd-ready-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let settings = DownloaderSettings {
+            binary: "sh".into(),
+            output_dir: Some(dir.clone()),
+            ..DownloaderSettings::default()
+        };
+        assert_eq!(readiness(&settings), Ok(()));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let settings = DownloaderSettings {
+            binary: dir.join("missing"),
+            output_dir: Some(dir.join("missing")),
+            ..DownloaderSettings::default()
+        };
+        assert!(readiness(&settings).unwrap_err().ends_with("missing is not an executable file"));
+        let settings = DownloaderSettings { binary: "sh".into(), ..settings };
+        assert!(readiness(&settings).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+        let now = Instant::now();
+        assert_eq!(secs_until(Some(now + Duration::from_secs(2)), now), Some(2.0));
+        assert_eq!(secs_until(Some(now), now + Duration::from_secs(3)), Some(-3.0));
+    }
+}
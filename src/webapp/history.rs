@@ -0,0 +1,184 @@
+//! The history page: past downloads with filters and totals per day, and
+//! buttons to queue them again or delete their files
+use super::api::Api;
+use super::auth::{Auth, Identity};
+use super::dates::utc;
+use super::send::{given, render};
+use crate::history::{DayTotal, Query, Record, Status};
+use crate::humanize_bytes;
+use crate::job::JobOptions;
+use askama::Template;
+use serde::Deserialize;
+use warp::{
+    http::StatusCode,
+    reply::Response,
+    Filter, Rejection,
+};
+
+/// Downloads per page
+const PAGE_SIZE: u32 = 50;
+
+/// The filter form. Empty fields match everything.
+#[derive(Debug, Default, Deserialize)]
+struct PageParams {
+    text: Option<String>,
+    status: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    /// Counting from 1
+    page: Option<u32>,
+}
+
+impl PageParams {
+    fn page(&self) -> u32 {
+        self.page.unwrap_or(1).max(1)
+    }
+    fn query(&self) -> Result<Query, String> {
+        Ok(Query {
+            text: given(&self.text),
+            status: given(&self.status).map(|s| s.parse()).transpose()?,
+            since: given(&self.since),
+            until: given(&self.until),
+            subdir: None,
+            // one more than shown, to know whether there is a next page
+            limit: Some(PAGE_SIZE + 1),
+            offset: Some((self.page() - 1).saturating_mul(PAGE_SIZE)),
+        })
+    }
+}
+
+/// A record as shown in the table
+struct Row {
+    id: i64,
+    finished: String,
+    title: String,
+    url: String,
+    status: &'static str,
+    failed: bool,
+    reason: String,
+    size: String,
+    rate: String,
+    attempts: u32,
+    /// Set while the file can be deleted
+    path: Option<String>,
+    log: Vec<String>,
+    options: JobOptions,
+}
+
+impl From<Record> for Row {
+    fn from(r: Record) -> Self {
+        Self {
+            id: r.id,
+            finished: utc(r.finished),
+            title: r.title.unwrap_or_else(|| r.url.clone()),
+            url: r.url,
+            status: r.status.as_str(),
+            failed: r.status == Status::Failed,
+            reason: r.reason,
+            size: humanize_bytes(r.bytes),
+            rate: r.avg_rate.map(|r| format!("{}/s", humanize_bytes(r))).unwrap_or_default(),
+            attempts: r.attempts,
+            path: r.path.filter(|_| r.deleted.is_none() && r.status == Status::Finished),
+            log: r.log,
+            options: r.options,
+        }
+    }
+}
+
+struct Total {
+    day: String,
+    count: u64,
+    size: String,
+}
+
+impl From<DayTotal> for Total {
+    fn from(t: DayTotal) -> Self {
+        Self {
+            day: t.day,
+            count: t.count,
+            size: humanize_bytes(t.bytes),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "history.html")]
+struct HistoryPage<'a> {
+    csrf: &'a str,
+    text: String,
+    /// Names for the status filter, with the one chosen
+    statuses: Vec<(&'static str, bool)>,
+    since: String,
+    until: String,
+    error: Option<String>,
+    rows: Vec<Row>,
+    totals: Vec<Total>,
+    page: u32,
+    more: bool,
+}
+
+async fn page(identity: Identity, params: PageParams, api: Api) -> Response {
+    let mut page = HistoryPage {
+        csrf: identity.csrf.as_deref().unwrap_or_default(),
+        text: params.text.clone().unwrap_or_default(),
+        statuses: [Status::Finished, Status::Failed, Status::Cancelled, Status::Skipped]
+            .into_iter()
+            .map(|s| (s.as_str(), params.status.as_deref() == Some(s.as_str())))
+            .collect(),
+        since: params.since.clone().unwrap_or_default(),
+        until: params.until.clone().unwrap_or_default(),
+        error: None,
+        rows: vec![],
+        totals: vec![],
+        page: params.page(),
+        more: false,
+    };
+    let found = async {
+        let history = api.history().map_err(|e| e.message)?;
+        let query = params.query()?;
+        let records = history.search(query.clone()).await?;
+        let totals = history.totals(query).await?;
+        Ok::<_, String>((records, totals))
+    };
+    match found.await {
+        Ok((mut records, totals)) => {
+            page.more = records.len() > PAGE_SIZE as usize;
+            records.truncate(PAGE_SIZE as usize);
+            page.rows = records.into_iter().map(Row::from).collect();
+            page.totals = totals.into_iter().map(Total::from).collect();
+        }
+        Err(e) => page.error = Some(e),
+    }
+    render(page, StatusCode::OK)
+}
+
+/// `/history`. Changes go through the API.
+pub fn route(api: Api, auth: Auth) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("history")
+        .and(warp::get())
+        .and(auth.allow())
+        .and(warp::query::<PageParams>())
+        .and(warp::any().map(move || api.clone()))
+        .then(page)
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+
+    #[test]
+    fn check_query() {
+        let params = PageParams {
+            text: Some(" cats ".into()),
+            status: Some("".into()),
+            page: Some(3),
+            ..PageParams::default()
+        };
+        let query = params.query().unwrap();
+        assert_eq!(query.text.as_deref(), Some("cats"));
+        assert_eq!(query.status, None);
+        assert_eq!(query.offset, Some(2 * PAGE_SIZE));
+        let bad = PageParams { status: Some("gone".into()), ..PageParams::default() };
+        assert!(bad.query().is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! Accepts web connections on any number of TCP and unix sockets and hands
+//! them to warp as one stream, with TLS on the TCP ones if configured.
+use crate::config::ListenAddr;
+use crate::Anything;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep, timeout, Duration};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, info, warn};
+
+/// How long a client may take to finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after a failed accept, e.g. when out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// The path is removed again when the server stops
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Whether other hosts can connect
+    pub fn is_public(&self) -> bool {
+        match self {
+            Listener::Tcp(l) => l.local_addr().is_ok_and(|a| !a.ip().is_loopback()),
+            Listener::Unix(..) => false,
+        }
+    }
+}
+
+/// Binds every address, replacing stale unix socket files. Unix sockets get
+/// the group and mode, like the control socket. If any address fails, the
+/// socket files created so far are removed.
+pub async fn bind(addrs: &[ListenAddr], gid: Option<u32>, mode: Option<u32>) -> Anything<Vec<Listener>> {
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        let listener = match addr {
+            ListenAddr::Tcp(addr) => TcpListener::bind(addr)
+                .await
+                .map(Listener::Tcp)
+                .map_err(|e| format!("Could not listen on {addr}: {e}").into()),
+            ListenAddr::Unix(path) => {
+                crate::unixsocket::bind(path, gid, mode).await.map(|l| Listener::Unix(l, path.clone()))
+            }
+        };
+        match listener {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                remove_files(&listeners);
+                return Err(e);
+            }
+        }
+    }
+    Ok(listeners)
+}
+
+/// Removes the socket files of the unix listeners
+fn remove_files(listeners: &[Listener]) {
+    for listener in listeners {
+        if let Listener::Unix(_, path) = listener {
+            _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A client connection of any kind
+pub enum Conn {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+macro_rules! delegate {
+    ($self:ident, $method:ident($($arg:expr),*)) => {
+        match $self.get_mut() {
+            Conn::Tcp(s) => Pin::new(s).$method($($arg),*),
+            Conn::Unix(s) => Pin::new(s).$method($($arg),*),
+            Conn::Tls(s) => Pin::new(s.as_mut()).$method($($arg),*),
+        }
+    };
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        delegate!(self, poll_write(cx, buf))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, poll_flush(cx))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, poll_shutdown(cx))
+    }
+}
+
+async fn accept(listener: &Listener, tls: &Option<TlsAcceptor>, tx: &mpsc::Sender<io::Result<Conn>>) -> io::Result<()> {
+    match listener {
+        Listener::Tcp(l) => {
+            let (stream, peer) = l.accept().await?;
+            match tls.clone() {
+                // handshake in the background, so a slow client does not
+                // hold up the others
+                Some(acceptor) => {
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => _ = tx.send(Ok(Conn::Tls(Box::new(stream)))).await,
+                            Ok(Err(e)) => debug!("TLS handshake with {peer} failed: {e}"),
+                            Err(_) => debug!("TLS handshake with {peer} timed out"),
+                        }
+                    });
+                }
+                None => _ = tx.send(Ok(Conn::Tcp(stream))).await,
+            }
+        }
+        Listener::Unix(l, _) => {
+            let (stream, _) = l.accept().await?;
+            _ = tx.send(Ok(Conn::Unix(stream))).await;
+        }
+    }
+    Ok(())
+}
+
+async fn accept_loop(
+    listener: Listener,
+    tls: Option<TlsAcceptor>,
+    tx: mpsc::Sender<io::Result<Conn>>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            res = accept(&listener, &tls, &tx) => if let Err(e) = res {
+                warn!("Could not accept a web connection: {e}");
+                sleep(ACCEPT_BACKOFF).await;
+            },
+            _ = crate::shutdown::requested(&mut shutdown_rx) => break,
+        }
+    }
+    if let Listener::Unix(_, path) = listener {
+        _ = tokio::fs::remove_file(path).await;
+    }
+}
+
+/// Connections from all listeners, until shutdown. TLS only applies to TCP,
+/// unix sockets are meant for a reverse proxy on the same host.
+pub fn incoming(
+    listeners: Vec<Listener>,
+    tls: Option<TlsAcceptor>,
+    shutdown_rx: watch::Receiver<bool>,
+) -> ReceiverStream<io::Result<Conn>> {
+    let (tx, rx) = mpsc::channel(listeners.len().max(1));
+    for listener in listeners {
+        match &listener {
+            Listener::Tcp(l) => match l.local_addr() {
+                Ok(addr) if tls.is_some() => info!("Web server listening on https://{addr}"),
+                Ok(addr) => info!("Web server listening on http://{addr}"),
+                Err(_) => {}
+            },
+            Listener::Unix(_, path) => info!("Web server listening on {}", path.display()),
+        }
+        tokio::spawn(accept_loop(listener, tls.clone(), tx.clone(), shutdown_rx.clone()));
+    }
+    ReceiverStream::new(rx)
+}
@@ -0,0 +1,294 @@
+//! `/metrics` in the Prometheus text format. Counters are kept from the
+//! downloader's messages, gauges are read from the tracker when scraped.
+use super::auth::{self, Auth, Identity};
+use crate::rate::Counter;
+use crate::history::Status;
+use crate::tracker::Tracker;
+use crate::{DownloaderMsg, HoldReason};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, watch};
+use tokio::time::{Duration, Instant};
+use warp::{
+    http::header,
+    reply::{self, Response},
+    Filter, Rejection, Reply,
+};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+/// Upper bounds of the spawn latency buckets, in seconds
+const SPAWN_BUCKETS: [f64; 8] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    buckets: [u64; SPAWN_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = SPAWN_BUCKETS.iter().position(|le| value <= *le) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Why a job ended or a downloader run failed. Labels only take these
+/// values, so errors with their own text do not make new series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Reason {
+    Finished,
+    Cancelled,
+    Skipped,
+    ExitCode,
+    Io,
+    Killed,
+}
+
+impl Reason {
+    /// Of a hold that is caused by an error
+    fn of_hold(hold: &HoldReason) -> Option<Self> {
+        match hold {
+            HoldReason::User => None,
+            HoldReason::ExitCode { .. } => Some(Reason::ExitCode),
+            HoldReason::Io { .. } => Some(Reason::Io),
+            HoldReason::Killed => Some(Reason::Killed),
+        }
+    }
+    fn as_str(self) -> &'static str {
+        match self {
+            Reason::Finished => "finished",
+            Reason::Cancelled => "cancelled",
+            Reason::Skipped => "skipped",
+            Reason::ExitCode => "exit_code",
+            Reason::Io => "io",
+            Reason::Killed => "killed",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    downloaded_bytes: u64,
+    counter: Counter,
+    /// Whether a downloader process is running
+    active: bool,
+    /// Jobs that left the downloader, by status and reason
+    ended: BTreeMap<(Status, Reason), u64>,
+    /// Downloader runs that failed, by reason
+    failures: BTreeMap<Reason, u64>,
+    /// Of the current job, failed jobs end with it
+    last_failure: Option<Reason>,
+    stuck: u64,
+    /// Downloader runs after the first of a job
+    retries: u64,
+    hold_since: Option<Instant>,
+    /// Of the holds that ended
+    hold_time: Duration,
+    /// Set until the downloader process writes its first line
+    spawned: Option<Instant>,
+    spawn_latency: Histogram,
+}
+
+impl Counters {
+    fn update(&mut self, msg: DownloaderMsg, now: Instant) {
+        use DownloaderMsg::*;
+        let output = matches!(msg, Starting(_) | Downloading { .. } | Moved(_) | Saved(_) | Output(_) | Info(_));
+        if let Some(spawned) = self.spawned.filter(|_| output) {
+            self.spawn_latency.observe(now.duration_since(spawned).as_secs_f64());
+            self.spawned = None;
+        }
+        if matches!(msg, Spawned { .. } | Idle | Finished { .. }) {
+            self.end_hold(now);
+        }
+        match msg {
+            Spawned { attempt } => {
+                if attempt > 1 {
+                    self.retries += 1;
+                }
+                self.active = true;
+                self.counter = Counter::default();
+                self.spawned = Some(now);
+                self.last_failure = None;
+            }
+            Downloading { downloaded_bytes, .. } => {
+                self.downloaded_bytes += self.counter.delta(downloaded_bytes);
+            }
+            Hold(reason) => {
+                self.active = false;
+                self.spawned = None;
+                if let Some(reason) = Reason::of_hold(&reason) {
+                    *self.failures.entry(reason).or_default() += 1;
+                    self.last_failure = Some(reason);
+                }
+                self.hold_since.get_or_insert(now);
+            }
+            Finished { status, .. } => {
+                self.active = false;
+                let reason = match status {
+                    Status::Finished => Reason::Finished,
+                    Status::Cancelled => Reason::Cancelled,
+                    Status::Skipped => Reason::Skipped,
+                    // failed jobs were held before, with the reason
+                    Status::Failed => self.last_failure.take().unwrap_or(Reason::Io),
+                };
+                *self.ended.entry((status, reason)).or_default() += 1;
+            }
+            Idle => self.active = false,
+            Stuck => self.stuck += 1,
+            Starting(_) | Moved(_) | PostProcessing | QueueUpdate(_) | Current(_) | ConfigReloaded(_) | Saved(_)
+            | Output(_) | Info(_) | StateChanged(_) => {}
+        }
+    }
+    fn end_hold(&mut self, now: Instant) {
+        if let Some(since) = self.hold_since.take() {
+            self.hold_time += now.duration_since(since);
+        }
+    }
+    /// The current hold so far
+    fn holding(&self, now: Instant) -> Duration {
+        self.hold_since.map(|since| now.duration_since(since)).unwrap_or_default()
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP {name} {help}");
+    _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn render(counters: &Counters, tracker: &Tracker, now: Instant) -> String {
+    let mut out = String::new();
+    let gauges = [
+        ("downd_queue_length", "Jobs waiting in the queue", tracker.queue.len() as f64),
+        ("downd_active_downloads", "Downloader processes running", u8::from(counters.active).into()),
+        ("downd_download_rate_bytes", "Current download rate in bytes per second", tracker.rate().unwrap_or(0) as f64),
+        ("downd_holding", "Whether the downloader waits for the user", u8::from(counters.hold_since.is_some()).into()),
+        ("downd_hold_current_seconds", "How long the current hold has lasted", counters.holding(now).as_secs_f64()),
+    ];
+    for (name, help, value) in gauges {
+        header(&mut out, name, "gauge", help);
+        _ = writeln!(out, "{name} {value}");
+    }
+    let hold_total = counters.hold_time + counters.holding(now);
+    let totals = [
+        ("downd_downloaded_bytes_total", "Bytes downloaded, across all attempts", counters.downloaded_bytes as f64),
+        ("downd_stuck_total", "Times the downloader stopped making progress", counters.stuck as f64),
+        ("downd_retries_total", "Downloader runs that retried a job", counters.retries as f64),
+        ("downd_hold_seconds_total", "Time spent holding", hold_total.as_secs_f64()),
+    ];
+    for (name, help, value) in totals {
+        header(&mut out, name, "counter", help);
+        _ = writeln!(out, "{name} {value}");
+    }
+    header(&mut out, "downd_jobs_ended_total", "counter", "Jobs that left the downloader, by status and reason");
+    for ((status, reason), count) in &counters.ended {
+        _ = writeln!(out, "downd_jobs_ended_total{{status=\"{}\",reason=\"{}\"}} {count}", status.as_str(), reason.as_str());
+    }
+    header(&mut out, "downd_download_failures_total", "counter", "Downloader runs that failed, by reason");
+    for (reason, count) in &counters.failures {
+        _ = writeln!(out, "downd_download_failures_total{{reason=\"{}\"}} {count}", reason.as_str());
+    }
+    let name = "downd_spawn_latency_seconds";
+    let histogram = &counters.spawn_latency;
+    header(&mut out, name, "histogram", "Time from starting the downloader to its first line of output");
+    let mut cumulative = 0;
+    for (le, count) in SPAWN_BUCKETS.iter().zip(histogram.buckets) {
+        cumulative += count;
+        _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+    }
+    _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", histogram.count);
+    _ = writeln!(out, "{name}_sum {}", histogram.sum);
+    _ = writeln!(out, "{name}_count {}", histogram.count);
+    out
+}
+
+/// Shared between the collector and the route
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Mutex<Counters>>);
+
+/// Counts the downloader's messages
+pub async fn collector(mut update_rx: broadcast::Receiver<DownloaderMsg>, metrics: Metrics) {
+    use broadcast::error::RecvError;
+    loop {
+        match update_rx.recv().await {
+            Ok(msg) => metrics.0.lock().unwrap().update(msg, Instant::now()),
+            Err(RecvError::Lagged(n)) => crate::warn!("metrics collector skipped {n} updates"),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+/// `/metrics`. Scrapers that fail to authenticate get a 401, not the login
+/// page.
+pub fn route(
+    metrics: Metrics,
+    state_rx: watch::Receiver<Tracker>,
+    auth: Auth,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get().or(warp::head()).unify())
+        .and(auth.allow())
+        .map(move |_: Identity| {
+            let text = render(&metrics.0.lock().unwrap(), &state_rx.borrow(), Instant::now());
+            reply::with_header(text, header::CONTENT_TYPE, CONTENT_TYPE).into_response()
+        })
+        .recover(auth::challenge)
+        .unify()
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+    use crate::job::{Job, JobOptions};
+    use crate::HoldReason;
+
+    #[test]
+    fn check_counters() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let progress = |downloaded_bytes| DownloaderMsg::Downloading {
+            downloaded_bytes,
+            total_bytes: None,
+            frag_index: None,
+            frag_count: None,
+            speed: None,
+        };
+        let job = Job::new("https://a.example/", JobOptions::default());
+        let mut c = Counters::default();
+        let msgs = [
+            (0, DownloaderMsg::Spawned { attempt: 1 }),
+            (300, DownloaderMsg::Starting(None)),
+            (400, progress(1000)),
+            (500, progress(3000)),
+            (600, DownloaderMsg::Hold(HoldReason::Killed)),
+            (700, DownloaderMsg::Spawned { attempt: 2 }),
+            (1900, progress(500)),
+            (2000, DownloaderMsg::Stuck),
+            (2100, DownloaderMsg::Hold(HoldReason::ExitCode { code: 1 })),
+            (5100, DownloaderMsg::Finished { job, status: Status::Failed, reason: "Error code 1".into() }),
+            (5200, DownloaderMsg::Hold(HoldReason::User)),
+        ];
+        for (ms, msg) in msgs {
+            c.update(msg, at(ms));
+        }
+        assert_eq!(c.downloaded_bytes, 3500);
+        assert_eq!((c.stuck, c.retries, c.active), (1, 1, false));
+        assert_eq!(c.hold_time, Duration::from_millis(3100));
+        assert_eq!(c.holding(at(6200)), Duration::from_secs(1));
+        assert_eq!(c.spawn_latency.count, 2);
+        assert_eq!(c.spawn_latency.buckets[1..5], [0, 1, 0, 1]);
+        let text = render(&c, &Tracker::new(), at(6200));
+        assert!(text.contains("downd_jobs_ended_total{status=\"failed\",reason=\"exit_code\"} 1\n"));
+        assert!(text.contains("downd_download_failures_total{reason=\"killed\"} 1\n"));
+        assert!(text.contains("downd_download_failures_total{reason=\"exit_code\"} 1\n"));
+        assert!(text.contains("downd_hold_seconds_total 4.1\n"));
+        assert!(text.contains("downd_retries_total 1\n"));
+        assert!(text.contains("downd_spawn_latency_seconds_bucket{le=\"1\"} 1\n"));
+        assert!(text.contains("downd_spawn_latency_seconds_bucket{le=\"+Inf\"} 2\n"));
+    }
+}
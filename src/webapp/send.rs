@@ -0,0 +1,290 @@
+//! Queueing from other pages: `/send` takes a URL from a bookmarklet or a
+//! phone's share sheet, `/bookmarklet` builds the former and the web app
+//! manifest announces the latter.
+use super::api::{Api, ApiError};
+use super::auth::{Auth, AuthError, Identity};
+use crate::config::Scope;
+use crate::job::{Job, JobOptions, JobSpec};
+use crate::DownloaderCommand;
+use askama::Template;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use warp::{
+    http::{header, Method, StatusCode},
+    reply::{self, Response},
+    Filter, Rejection, Reply,
+};
+
+/// Largest form accepted by `/send`
+const FORM_LIMIT: u64 = 16 * 1024;
+
+const ICON: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 64 64"><rect width="64" height="64" rx="12" fill="#36c"/><path d="M32 12v30m-13-13 13 13 13-13M16 50h32" stroke="#fff" stroke-width="6" fill="none" stroke-linecap="round"/></svg>"##;
+
+/// What `/send` takes, from the query string or a form. Everything but the
+/// URL is optional.
+#[derive(Debug, Default, Deserialize)]
+struct SendParams {
+    url: Option<String>,
+    /// Share sheets often put the link in here instead of `url`
+    text: Option<String>,
+    /// A bearer token, for bookmarklets that cannot set headers
+    token: Option<String>,
+    csrf: Option<String>,
+    profile: Option<String>,
+    format: Option<String>,
+    audio_only: Option<String>,
+    rate_limit: Option<String>,
+    subdir: Option<String>,
+}
+
+/// Forms send empty fields for anything left blank
+pub(super) fn given(value: &Option<String>) -> Option<String> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+}
+
+impl SendParams {
+    fn url(&self) -> Option<String> {
+        given(&self.url).or_else(|| {
+            self.text
+                .as_deref()?
+                .split_whitespace()
+                .find(|w| w.starts_with("http://") || w.starts_with("https://"))
+                .map(String::from)
+        })
+    }
+    /// The job to queue: the profile's options, with the given ones on top
+    fn spec(&self, profiles: &BTreeMap<String, JobOptions>) -> Result<JobSpec, String> {
+        let url = self.url().ok_or("no url given")?;
+        let mut options = match given(&self.profile) {
+            Some(name) => profiles
+                .get(&name)
+                .cloned()
+                .ok_or_else(|| format!("unknown profile: {name}"))?,
+            None => JobOptions::default(),
+        };
+        if let Some(format) = given(&self.format) {
+            options.format = Some(format);
+        }
+        if let Some(flag) = given(&self.audio_only) {
+            options.audio_only = matches!(flag.as_str(), "1" | "true" | "on" | "yes");
+        }
+        if let Some(rate_limit) = given(&self.rate_limit) {
+            options.rate_limit = Some(rate_limit);
+        }
+        if let Some(subdir) = given(&self.subdir) {
+            options.subdir = Some(subdir);
+        }
+        let spec = JobSpec { url, options };
+        spec.validate()?;
+        Ok(spec)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "send.html")]
+struct SendPage<'a> {
+    message: &'a str,
+    spec: Option<&'a JobSpec>,
+    /// Ask before queueing
+    confirm: bool,
+    /// For the confirmation, with a session
+    csrf: Option<&'a str>,
+}
+
+#[derive(Template)]
+#[template(path = "bookmarklet.html")]
+struct BookmarkletPage<'a> {
+    auth: bool,
+    profiles: Vec<&'a str>,
+}
+
+/// Whether a request carries a bearer token, which only scripts and
+/// bookmarklets the user set up have. Browsers add cookies and basic auth to
+/// requests from any site on their own.
+fn has_token(authorization: Option<&str>, params: &SendParams) -> bool {
+    let bearer = authorization
+        .and_then(|h| h.split_once(' '))
+        .is_some_and(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"));
+    bearer || given(&params.token).is_some()
+}
+
+/// Renders a page, or answers 500 if that fails
+pub(super) fn render(page: impl Template, status: StatusCode) -> Response {
+    match page.render() {
+        Ok(html) => reply::with_status(reply::html(html), status).into_response(),
+        Err(e) => {
+            crate::error!("Could not render page: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Ctx {
+    api: Api,
+    auth: Auth,
+    profiles: Arc<BTreeMap<String, JobOptions>>,
+}
+
+async fn send(
+    params: SendParams,
+    method: Method,
+    authorization: Option<String>,
+    cookie: Option<String>,
+    accept: Option<String>,
+    ctx: Ctx,
+) -> Response {
+    let json = accept.is_some_and(|a| a.contains("application/json"));
+    let fail = |status: StatusCode, message: String| match json {
+        true => ApiError::new(status, message).into_response(),
+        false => render(SendPage { message: &message, spec: None, confirm: false, csrf: None }, status),
+    };
+    let token = has_token(authorization.as_deref(), &params);
+    let authorization = authorization.or_else(|| given(&params.token).map(|t| format!("Bearer {t}")));
+    let identity = match ctx.auth.check(method.clone(), authorization, cookie, params.csrf.clone()).await {
+        Ok(identity) => identity,
+        Err(AuthError::Missing) if !json => {
+            return reply::with_header(StatusCode::SEE_OTHER, header::LOCATION, "/login").into_response()
+        }
+        Err(e) => return fail(e.status(), e.to_string()),
+    };
+    let spec = match params.spec(&ctx.profiles) {
+        Ok(spec) => spec,
+        Err(e) => return fail(StatusCode::BAD_REQUEST, e),
+    };
+    // any site can link here or load it as an image, so a GET without a
+    // token only asks, and the confirmation posts
+    if method == Method::GET && !token {
+        if json {
+            return fail(StatusCode::METHOD_NOT_ALLOWED, "POST, or GET with a token".into());
+        }
+        let page = SendPage {
+            message: "Add to the queue?",
+            spec: Some(&spec),
+            confirm: true,
+            csrf: identity.csrf.as_deref(),
+        };
+        return render(page, StatusCode::OK);
+    }
+    if identity.scope < Scope::Control {
+        return fail(StatusCode::FORBIDDEN, "read-only access".into());
+    }
+    let job = Job::from(spec);
+    match ctx.api.send(DownloaderCommand::AddJobs(vec![job.clone()])).await {
+        Ok(()) if json => reply::with_status(reply::json(&job), StatusCode::CREATED).into_response(),
+        Ok(()) => render(SendPage { message: "Queued", spec: Some(&job.spec()), confirm: false, csrf: None }, StatusCode::CREATED),
+        Err(e) => fail(e.status, e.message),
+    }
+}
+
+fn manifest() -> Response {
+    let manifest = serde_json::json!({
+        "name": "downd",
+        "short_name": "downd",
+        "start_url": "/root",
+        "display": "standalone",
+        "icons": [{ "src": "/icon.svg", "sizes": "any", "type": "image/svg+xml" }],
+        "share_target": {
+            "action": "/send",
+            "method": "GET",
+            "params": { "title": "title", "text": "text", "url": "url" },
+        },
+    });
+    reply::with_header(reply::json(&manifest), header::CONTENT_TYPE, "application/manifest+json").into_response()
+}
+
+/// `/send`, `/bookmarklet` and the manifest with its icon
+pub fn routes(
+    api: Api,
+    auth: Auth,
+    profiles: BTreeMap<String, JobOptions>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let ctx = Ctx {
+        api,
+        auth: auth.clone(),
+        profiles: Arc::new(profiles),
+    };
+    let params = warp::get().and(warp::query::<SendParams>()).or(warp::post()
+        .and(warp::body::content_length_limit(FORM_LIMIT))
+        .and(warp::body::form::<SendParams>()))
+        .unify();
+    let send = warp::path!("send")
+        .and(params)
+        .and(warp::method())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::cookie::optional::<String>(super::auth::SESSION_COOKIE))
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::any().map({
+            let ctx = ctx.clone();
+            move || ctx.clone()
+        }))
+        .then(send);
+    let bookmarklet = warp::path!("bookmarklet")
+        .and(warp::get())
+        .and(auth.allow())
+        .map(move |_: Identity| {
+            let page = BookmarkletPage {
+                auth: ctx.auth.enabled(),
+                profiles: ctx.profiles.keys().map(String::as_str).collect(),
+            };
+            render(page, StatusCode::OK)
+        });
+    let manifest = warp::path!("manifest.webmanifest").and(warp::get()).map(manifest);
+    let icon = warp::path!("icon.svg")
+        .and(warp::get())
+        .map(|| reply::with_header(ICON, header::CONTENT_TYPE, "image/svg+xml").into_response());
+    send.or(bookmarklet)
+        .unify()
+        .or(manifest)
+        .unify()
+        .or(icon)
+        .unify()
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+
+    #[test]
+    fn check_params() {
+        let profiles = BTreeMap::from([(
+            "audio".to_string(),
+            JobOptions { audio_only: true, subdir: Some("music".into()), ..JobOptions::default() },
+        )]);
+        let shared = SendParams {
+            text: Some("Look at this https://example.com/v/1 !".into()),
+            profile: Some("audio".into()),
+            subdir: Some("".into()),
+            ..SendParams::default()
+        };
+        let spec = shared.spec(&profiles).unwrap();
+        assert_eq!(spec.url, "https://example.com/v/1");
+        assert_eq!(spec.options.subdir.as_deref(), Some("music"));
+        assert!(spec.options.audio_only);
+        let overridden = SendParams {
+            url: Some("https://example.com/v/2".into()),
+            profile: Some("audio".into()),
+            audio_only: Some("false".into()),
+            ..SendParams::default()
+        };
+        assert!(!overridden.spec(&profiles).unwrap().options.audio_only);
+        let unknown = SendParams { url: Some("x".into()), profile: Some("video".into()), ..SendParams::default() };
+        assert!(unknown.spec(&profiles).is_err());
+        assert!(SendParams::default().spec(&profiles).is_err());
+    }
+
+    #[test]
+    fn check_token() {
+        let none = SendParams::default();
+        assert!(has_token(Some("bearer abc"), &none));
+        assert!(!has_token(Some("Basic YWRtaW46c2VjcmV0"), &none));
+        assert!(!has_token(None, &none));
+        assert!(!has_token(None, &SendParams { token: Some(" ".into()), ..SendParams::default() }));
+        assert!(has_token(None, &SendParams { token: Some("abc".into()), ..SendParams::default() }));
+    }
+}
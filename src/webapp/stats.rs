@@ -0,0 +1,97 @@
+//! The statistics page: totals for today, this week and all time, with bar
+//! charts of the busiest hours and domains
+use super::api::Api;
+use super::auth::{Auth, Identity};
+use super::send::render;
+use crate::history::{Period, Stats};
+use crate::humanize_bytes;
+use askama::Template;
+use warp::{
+    http::StatusCode,
+    reply::Response,
+    Filter, Rejection,
+};
+
+/// A row of the totals table
+struct PeriodRow {
+    name: &'static str,
+    size: String,
+    time: String,
+    rate: String,
+    finished: u64,
+    failed: u64,
+}
+
+impl PeriodRow {
+    fn new(name: &'static str, p: &Period) -> Self {
+        let secs = p.seconds.round() as u64;
+        Self {
+            name,
+            size: humanize_bytes(p.bytes),
+            time: format!("{}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60),
+            rate: p.avg_rate.map(|r| format!("{}/s", humanize_bytes(r))).unwrap_or_default(),
+            finished: p.finished,
+            failed: p.failed,
+        }
+    }
+}
+
+/// One bar of a chart
+struct Bar {
+    label: String,
+    value: String,
+    /// Of the longest bar
+    percent: u64,
+}
+
+fn bars(values: impl Iterator<Item = (String, String, u64)> + Clone) -> Vec<Bar> {
+    let max = values.clone().map(|(_, _, v)| v).max().unwrap_or(0).max(1);
+    values.map(|(label, value, v)| Bar { label, value, percent: v * 100 / max }).collect()
+}
+
+#[derive(Template)]
+#[template(path = "stats.html")]
+struct StatsPage {
+    error: Option<String>,
+    periods: Vec<PeriodRow>,
+    hours: Vec<Bar>,
+    domains: Vec<Bar>,
+}
+
+impl From<Stats> for StatsPage {
+    fn from(stats: Stats) -> Self {
+        let hours = stats.hours.iter().map(|h| (format!("{:02}", h.hour), humanize_bytes(h.bytes), h.bytes));
+        let domains = stats
+            .top_domains
+            .iter()
+            .map(|d| (d.domain.clone(), format!("{} jobs, {}", d.jobs, humanize_bytes(d.bytes)), d.jobs));
+        Self {
+            error: None,
+            periods: vec![
+                PeriodRow::new("Today", &stats.today),
+                PeriodRow::new("This week", &stats.week),
+                PeriodRow::new("All time", &stats.all_time),
+            ],
+            hours: bars(hours),
+            domains: bars(domains),
+        }
+    }
+}
+
+async fn page(_: Identity, api: Api) -> Response {
+    let stats = async { api.history().map_err(|e| e.message)?.stats().await };
+    let page = match stats.await {
+        Ok(stats) => StatsPage::from(stats),
+        Err(e) => StatsPage { error: Some(e), periods: vec![], hours: vec![], domains: vec![] },
+    };
+    render(page, StatusCode::OK)
+}
+
+/// `/stats`
+pub fn route(api: Api, auth: Auth) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("stats")
+        .and(warp::get())
+        .and(auth.allow())
+        .and(warp::any().map(move || api.clone()))
+        .then(page)
+}
@@ -0,0 +1,107 @@
+//! HTTPS for the web server. The certificate and key are checked for
+//! changes periodically, so a renewed certificate is picked up without a
+//! restart.
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::sync::watch;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tokio_rustls::rustls::{
+    self,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info};
+
+/// How often the certificate files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+fn provider() -> rustls::crypto::CryptoProvider {
+    rustls::crypto::ring::default_provider()
+}
+
+/// Reads a PEM certificate chain and its private key
+pub fn load(cert: &Path, key: &Path) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Could not read certificate {cert:?}: {e}"))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in {cert:?}"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| format!("Could not read key {key:?}: {e}"))?;
+    CertifiedKey::from_der(certs, key, &provider()).map_err(|e| format!("Unusable certificate or key: {e}"))
+}
+
+/// Hands out whatever certificate was loaded last
+#[derive(Debug)]
+struct Resolver(RwLock<Arc<CertifiedKey>>);
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().unwrap().clone())
+    }
+}
+
+fn modified(paths: &[&Path]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Loads the certificate and keeps it up to date until shutdown
+pub fn acceptor(
+    cert: PathBuf,
+    key: PathBuf,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> Result<TlsAcceptor, String> {
+    let resolver = Arc::new(Resolver(RwLock::new(Arc::new(load(&cert, &key)?))));
+    let config = ServerConfig::builder_with_provider(Arc::new(provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    tokio::spawn(async move {
+        let mut seen = modified(&[&cert, &key]);
+        let mut ticks = interval(POLL_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {},
+                _ = crate::shutdown::requested(&mut shutdown_rx) => break,
+            }
+            let now = modified(&[&cert, &key]);
+            if now == seen {
+                continue;
+            }
+            // keep the old certificate if the new one is not complete yet,
+            // and try again on the next change
+            seen = now;
+            match load(&cert, &key) {
+                Ok(certified) => {
+                    *resolver.0.write().unwrap() = Arc::new(certified);
+                    info!("Reloaded TLS certificate from {cert:?}");
+                }
+                Err(e) => error!("Keeping the old TLS certificate: {e}"),
+            }
+        }
+        debug!("TLS certificate watcher stopped");
+    });
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+
+    #[test]
+    fn check_load() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+        assert!(load(&dir.join("cert.pem"), &dir.join("key.pem")).is_ok());
+        // key and certificate swapped
+        assert!(load(&dir.join("key.pem"), &dir.join("cert.pem")).is_err());
+    }
+}
@@ -3,6 +3,20 @@
 mod parser;
 use parser::*;
 use crate::Url;
+use crate::job::Job;
+use crate::config::DownloaderSettings;
+use crate::history::Status;
+use crate::state::{DaemonState, HoldReason};
+use serde::Deserialize;
+
+/// What yt-dlp tells about the media besides the title. Fields it does not
+/// know are left out.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct MediaInfo {
+    /// In seconds
+    pub duration: Option<f64>,
+    pub description: Option<String>,
+}
 
 #[derive(Debug, Clone)]
 pub enum DownloaderMsg {
@@ -12,12 +26,33 @@ pub enum DownloaderMsg {
         total_bytes: Option<u64>,
         frag_index: Option<u64>,
         frag_count: Option<u64>,
+        /// Bytes per second, as yt-dlp measured it
+        speed: Option<u64>,
     },
+    /// The download is done and the files are being processed
+    PostProcessing,
     Moved(Option<String>),
     Stuck,
     Idle,
-    Hold(String),
-    QueueUpdate(Vec<Url>),
+    Hold(HoldReason),
+    QueueUpdate(Vec<Job>),
+    /// A job left the queue and is being downloaded
+    Current(Job),
+    /// The current job is done. The reason tells more, e.g. the error of a
+    /// job that failed and was skipped.
+    Finished { job: Job, status: Status, reason: String },
+    /// The configuration was reloaded. One line per changed setting.
+    ConfigReloaded(Vec<String>),
+    /// A downloader process was started for the current job, counting from 1
+    Spawned { attempt: u32 },
+    /// A downloaded file was moved to its final path
+    Saved(String),
+    /// Downloader output that is not progress, usually a warning or an error
+    Output(String),
+    /// Details of the downloaded media, sent with `Saved`
+    Info(MediaInfo),
+    /// The daemon's state changed, sent along with the messages that caused it
+    StateChanged(DaemonState),
 }
 
 impl DownloaderMsg {
@@ -78,15 +113,38 @@ impl TryFrom<String> for DownloaderMsg {
     }
 }
 
-pub fn ytdlp_command(url: impl AsRef<std::ffi::OsStr>) -> tokio::process::Command {
-    let mut c = tokio::process::Command::new("/usr/bin/yt-dlp");
+pub fn ytdlp_command(settings: &DownloaderSettings, job: &Job) -> tokio::process::Command {
+    let mut c = tokio::process::Command::new(&settings.binary);
     c.arg("--progress")
-    .arg("--progress-template=download:DOWNLOAD|%(progress.downloaded_bytes)d|%(progress.total_bytes,progress.total_bytes_estimate)d|%(progress.fragment_index)d|%(progress.fragment_count)d|")
+    .arg("--progress-template=download:DOWNLOAD|%(progress.downloaded_bytes)d|%(progress.total_bytes,progress.total_bytes_estimate)d|%(progress.fragment_index)d|%(progress.fragment_count)d|%(progress.speed)d|")
+    .arg("-O").arg("post_process:PROCESSING|%(filepath)s")
     .arg("-O").arg("after_move:MOVED|%(title,alt_title,fulltitle,filename)s")
+    .arg("-O").arg("after_move:FILE|%(filepath)s")
+    .arg("-O").arg("after_move:META|%(.{duration,description})j")
     .arg("-O").arg("video:START|%(title,alt_title,fulltitle,filename)s")
     .arg("--newline")
-    .arg("-q")
+    .arg("-q");
+    let options = &job.options;
+    match (&settings.output_dir, &options.subdir) {
+        (Some(dir), Some(sub)) => { c.arg("--paths").arg(dir.join(sub)); }
+        (Some(dir), None) => { c.arg("--paths").arg(dir); }
+        (None, Some(sub)) => { c.arg("--paths").arg(sub); }
+        (None, None) => {}
+    }
+    if let Some(dir) = &settings.temp_dir {
+        c.arg("--paths").arg(format!("temp:{}", dir.display()));
+    }
+    if let Some(rate) = options.rate_limit.as_ref().or(settings.rate_limit.as_ref()) {
+        c.arg("--limit-rate").arg(rate);
+    }
+    if let Some(format) = &options.format {
+        c.arg("-f").arg(format);
+    }
+    if options.audio_only {
+        c.arg("-x");
+    }
+    c.args(&settings.args)
     .arg("--")
-    .arg(url);
+    .arg(&job.url);
     c
 }
@@ -3,7 +3,7 @@ use nom::{
     branch::alt,
     bytes::complete::tag,
     character::complete::{char, digit1, not_line_ending},
-    combinator::{map, map_res},
+    combinator::{map, map_res, opt},
     sequence::terminated,
     Finish, IResult,
 };
@@ -24,6 +24,7 @@ fn parse_download_line(input: &str) -> IResult<&str, DownloaderMsg> {
     let (i, total_bytes) = digit_field(i)?;
     let (i, frag_index) = digit_field(i)?;
     let (i, frag_count) = digit_field(i)?;
+    let (i, speed) = opt(digit_field)(i)?;
     Ok((
         i,
         DownloaderMsg::Downloading {
@@ -31,6 +32,7 @@ fn parse_download_line(input: &str) -> IResult<&str, DownloaderMsg> {
             total_bytes,
             frag_index,
             frag_count,
+            speed: speed.flatten(),
         },
     ))
 }
@@ -42,6 +44,24 @@ fn parse_moved_line(input: &str) -> IResult<&str, DownloaderMsg> {
     Ok((i, DownloaderMsg::Moved(title)))
 }
 
+fn parse_file_line(input: &str) -> IResult<&str, DownloaderMsg> {
+    let (i, _) = tag("FILE|")(input)?;
+    let (i, path) = map(not_line_ending, String::from)(i)?;
+    Ok((i, DownloaderMsg::Saved(path)))
+}
+
+fn parse_meta_line(input: &str) -> IResult<&str, DownloaderMsg> {
+    let (i, _) = tag("META|")(input)?;
+    let (i, info) = map_res(not_line_ending, serde_json::from_str)(i)?;
+    Ok((i, DownloaderMsg::Info(info)))
+}
+
+fn parse_processing_line(input: &str) -> IResult<&str, DownloaderMsg> {
+    let (i, _) = tag("PROCESSING|")(input)?;
+    let (i, _) = not_line_ending(i)?;
+    Ok((i, DownloaderMsg::PostProcessing))
+}
+
 fn parse_title_line(input: &str) -> IResult<&str, DownloaderMsg> {
     let (i, _) = tag("START|")(input)?;
     let (i, title) = map(not_line_ending, String::from)(i)?;
@@ -50,6 +70,22 @@ fn parse_title_line(input: &str) -> IResult<&str, DownloaderMsg> {
 }
 
 pub fn parse_progress_update_line(line: &str) -> Result<DownloaderMsg, nom::error::Error<&str>> {
-    let mut p = alt((parse_download_line, parse_moved_line, parse_title_line));
+    let mut p = alt((parse_download_line, parse_moved_line, parse_file_line, parse_meta_line, parse_processing_line, parse_title_line));
     p(line).finish().map(|x| x.1)
 }
+
+#[cfg(test)]
+mod checks {
+    use super::*;
+
+    #[test]
+    fn check_download_line() {
+        let msg = parse_progress_update_line("DOWNLOAD|500|1000|NA|NA|250|").unwrap();
+        assert!(matches!(msg, DownloaderMsg::Downloading { downloaded_bytes: 500, speed: Some(250), .. }));
+        // the speed is NA until yt-dlp has measured it, and older templates leave it out
+        let msg = parse_progress_update_line("DOWNLOAD|500|NA|3|10|NA|").unwrap();
+        assert!(matches!(msg, DownloaderMsg::Downloading { frag_index: Some(3), speed: None, .. }));
+        let msg = parse_progress_update_line("DOWNLOAD|500|1000|NA|NA|").unwrap();
+        assert!(matches!(msg, DownloaderMsg::Downloading { total_bytes: Some(1000), speed: None, .. }));
+    }
+}